@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rand::distributions::{Distribution, Uniform};
+
+use micro_grad::{Graph, MultiLayerPerceptron, Var};
+
+mod common;
+
+// A tanh neuron's weights and bias, stored in an arena graph
+type ArenaNeuron<'g> = (Vec<Var<'g, f64>>, Var<'g, f64>);
+
+// A training step of an MLP, rebuilding its Scalar graph against reusing an arena graph
+fn training_step(c: &mut Criterion) {
+    let (xs, ys) = common::data();
+    let mut group = c.benchmark_group("training step");
+
+    let mlp = MultiLayerPerceptron::new(3, &common::LAYER_SIZES).expect("Invalid model");
+    group.bench_function("rebuild scalar graph", |b| b.iter(|| common::scalar_step(&mlp, &xs, &ys)));
+
+    let graph = Graph::new();
+    let mut rng = rand::thread_rng();
+    let uniform = Uniform::new(-1.0, 1.0);
+    let mut num_inputs = xs[0].len();
+    let mut layers: Vec<Vec<ArenaNeuron>> = Vec::new();
+    for num_outputs in common::LAYER_SIZES {
+        layers.push((0..num_outputs).map(|_| {
+            let weights = (0..num_inputs).map(|_| graph.var(uniform.sample(&mut rng))).collect();
+            (weights, graph.var(uniform.sample(&mut rng)))
+        }).collect());
+        num_inputs = num_outputs;
+    }
+    let parameters: Vec<Var<f64>> = layers.iter().flatten()
+        .flat_map(|(weights, bias)| weights.iter().chain(std::iter::once(bias)).copied())
+        .collect();
+    let num_parameters = graph.len();
+
+    group.bench_function("reuse arena graph", |b| b.iter(|| {
+        let mut loss = graph.var(0.0);
+        for (x, y) in xs.iter().zip(ys) {
+            let mut hidden: Vec<Var<f64>> = x.iter().map(|v| graph.var(*v)).collect();
+            for layer in &layers {
+                hidden = layer.iter().map(|(weights, bias)| {
+                    let mut sum = *bias;
+                    for (weight, input) in weights.iter().zip(hidden.iter()) {
+                        sum = &sum + &(weight * input);
+                    }
+                    sum.tanh()
+                }).collect();
+            }
+            loss = &loss + &(&hidden[0] - &graph.var(y)).pow(2.0);
+        }
+        parameters.iter().for_each(|p| p.zero_grad());
+        loss.backward();
+        for p in &parameters {
+            p.add_to_data(p.get_grad() * -common::LEARNING_RATE);
+        }
+        let loss = loss.get_data();
+        graph.truncate(num_parameters);
+        loss
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, training_step);
+criterion_main!(benches);
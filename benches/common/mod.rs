@@ -0,0 +1,38 @@
+// Shared by the benchmarks that compare a faster way of training against rebuilding the Scalar graph every step
+
+use micro_grad::{Module, MultiLayerPerceptron, Scalar};
+
+pub const LEARNING_RATE: f64 = 0.05;
+pub const LAYER_SIZES: [usize; 3] = [16, 16, 1];
+
+pub fn data() -> ([Vec<f64>; 4], [f64; 4]) {
+    let xs = [
+        vec![2.0, 3.0, -1.0],
+        vec![3.0, -1.0, 0.5],
+        vec![0.5, 1.0, 1.0],
+        vec![1.0, 1.0, -1.0],
+    ];
+    let ys = [1.0, -1.0, -1.0, 1.0];
+    (xs, ys)
+}
+
+// The squared error of the model's predictions, as a graph
+pub fn loss(mlp: &MultiLayerPerceptron<f64>, xs: &[Vec<f64>], ys: &[Scalar<f64>]) -> Scalar<f64> {
+    let predictions = mlp.forward_batch(xs).expect("Wrong number of inputs").concat();
+    let mut loss = Scalar::new(0.0);
+    for (prediction, y) in predictions.iter().zip(ys) {
+        loss = &loss + &(prediction - y).pow(2.0);
+    }
+    loss
+}
+
+// One step of gradient descent, building the graph from scratch
+pub fn scalar_step(mlp: &MultiLayerPerceptron<f64>, xs: &[Vec<f64>], ys: &[f64]) -> f64 {
+    let loss = loss(mlp, xs, &ys.iter().map(|y| Scalar::new(*y)).collect::<Vec<_>>());
+    mlp.zero_grad();
+    loss.backward();
+    for p in mlp.parameters() {
+        p.add_to_data(p.get_grad() * -LEARNING_RATE);
+    }
+    loss.get_data()
+}
@@ -0,0 +1,32 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use micro_grad::{Module, Neuron, Scalar};
+
+const WIDTH: usize = 1000;
+
+// Backward through a wide neuron, built from a Mul and an Add per input against a neuron's fused dot node
+fn neuron_backward(c: &mut Criterion) {
+    let neuron: Neuron<f64> = Neuron::new(WIDTH).expect("Invalid neuron");
+    let inputs: Vec<Scalar<f64>> = (0..WIDTH).map(|i| Scalar::new(i as f64 / WIDTH as f64)).collect();
+    let mut group = c.benchmark_group("neuron backward");
+
+    let mut sum = neuron.bias.clone().expect("Neurons have a bias by default");
+    for (input, weight) in inputs.iter().zip(neuron.weights.iter()) {
+        sum = &sum + &(input * weight);
+    }
+    let unfused = sum.tanh();
+    group.bench_function("mul and add per input", |b| b.iter(|| {
+        neuron.zero_grad();
+        unfused.backward();
+    }));
+
+    let fused = neuron.forward(&inputs).expect("Wrong number of inputs");
+    group.bench_function("fused dot", |b| b.iter(|| {
+        neuron.zero_grad();
+        fused.backward();
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, neuron_backward);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use micro_grad::{Module, MultiLayerPerceptron, Scalar, Tape};
+
+mod common;
+
+// A training step of an MLP, rebuilding its graph against re-running a tape traced once
+fn training_step(c: &mut Criterion) {
+    let (xs, ys) = common::data();
+    let mut group = c.benchmark_group("training step");
+
+    let mlp = MultiLayerPerceptron::new(3, &common::LAYER_SIZES).expect("Invalid model");
+    group.bench_function("rebuild scalar graph", |b| b.iter(|| common::scalar_step(&mlp, &xs, &ys)));
+
+    let mlp = MultiLayerPerceptron::new(3, &common::LAYER_SIZES).expect("Invalid model");
+    let x_scalars: Vec<Vec<Scalar<f64>>> = xs.iter().map(|x| x.iter().map(|v| Scalar::new(*v)).collect()).collect();
+    let y_scalars: Vec<Scalar<f64>> = ys.iter().map(|y| Scalar::new(*y)).collect();
+    let predictions: Vec<Scalar<f64>> = x_scalars.iter()
+        .flat_map(|x| mlp.forward_scalars(x).expect("Wrong number of inputs"))
+        .collect();
+    let mut loss = Scalar::new(0.0);
+    for (prediction, y) in predictions.iter().zip(y_scalars.iter()) {
+        loss = &loss + &(prediction - y).pow(2.0);
+    }
+
+    let x_names: Vec<Vec<String>> = xs.iter().enumerate()
+        .map(|(i, x)| (0..x.len()).map(|j| format!("x{}_{}", i, j)).collect())
+        .collect();
+    let y_names: Vec<String> = (0..ys.len()).map(|i| format!("y{}", i)).collect();
+    let mut inputs: Vec<(&str, &Scalar<f64>)> = Vec::new();
+    for (i, x) in x_scalars.iter().enumerate() {
+        inputs.extend(x_names[i].iter().map(|name| name.as_str()).zip(x.iter()));
+        inputs.push((y_names[i].as_str(), &y_scalars[i]));
+    }
+    let mut tape = Tape::trace(&loss, &inputs, &mlp.parameters()).expect("Failed to trace the loss");
+
+    group.bench_function("re-run tape", |b| b.iter(|| {
+        for (i, x) in xs.iter().enumerate() {
+            for (name, value) in x_names[i].iter().zip(x.iter()) {
+                tape.set_input(name, *value).expect("Unknown input");
+            }
+            tape.set_input(&y_names[i], ys[i]).expect("Unknown input");
+        }
+        tape.refresh_parameters();
+        let loss = tape.forward();
+        tape.backward();
+        for (p, grad) in tape.parameter_grads() {
+            p.add_to_data(grad * -common::LEARNING_RATE);
+        }
+        loss
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, training_step);
+criterion_main!(benches);
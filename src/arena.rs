@@ -0,0 +1,426 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::{Display, Formatter, Result};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::ptr;
+
+use num_traits::Float;
+
+use crate::is_anomaly_detection_enabled;
+
+// The operation that produced a node. Operands are indices of earlier nodes.
+#[derive(Clone, Copy)]
+enum ArenaOp<T> {
+    Leaf,
+    Add(usize, usize),
+    Mul(usize, usize),
+    Pow(usize, T),
+    Exp(usize),
+    Tanh(usize),
+    Relu(usize),
+}
+
+impl<T> ArenaOp<T> {
+    fn operands(&self) -> Vec<usize> {
+        match *self {
+            ArenaOp::Leaf => Vec::new(),
+            ArenaOp::Add(a, b) | ArenaOp::Mul(a, b) => vec![a, b],
+            ArenaOp::Pow(a, _) | ArenaOp::Exp(a) | ArenaOp::Tanh(a) | ArenaOp::Relu(a) => vec![a],
+        }
+    }
+}
+
+impl<T: Display> Display for ArenaOp<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            ArenaOp::Leaf => write!(f, "leaf"),
+            ArenaOp::Add(..) => write!(f, "+"),
+            ArenaOp::Mul(..) => write!(f, "*"),
+            ArenaOp::Pow(_, power) => write!(f, "^{}", power),
+            ArenaOp::Exp(_) => write!(f, "exp"),
+            ArenaOp::Tanh(_) => write!(f, "tanh"),
+            ArenaOp::Relu(_) => write!(f, "relu"),
+        }
+    }
+}
+
+struct Node<T> {
+    data: T,
+    grad: T,
+    op: ArenaOp<T>,
+    // The graph's generation when the node was created, which a `Var` must match to use the node
+    generation: u64,
+    label: String,
+    // As for `Scalar::register_hook`
+    hooks: Vec<Box<dyn Fn(T) -> T>>,
+}
+
+impl<T> Node<T> {
+    // How anomaly reports and `Display` refer to the node
+    fn name(&self, index: usize) -> String {
+        if self.label.is_empty() { format!("#{}", index) } else { format!("'{}'", self.label) }
+    }
+}
+
+/// Graph storage as a single contiguous `Vec` of nodes, referenced by index. This avoids the per-node `Rc`,
+/// `RefCell` and closure allocations of `Scalar`, and since nodes can only refer to earlier nodes, the storage order
+/// is already a topological ordering.
+///
+/// For training, create the parameters first, note `len()`, and `truncate` back to it after each step. The storage
+/// is then reused, so steps after the first don't allocate.
+///
+/// Each `truncate` starts a new generation. A `Var` remembers the generation its node was created in, so using a
+/// `Var` whose node was dropped panics, even once a newer node has taken its index. Combining `Var`s from different
+/// graphs also panics.
+pub struct Graph<T> {
+    nodes: RefCell<Vec<Node<T>>>,
+    generation: Cell<u64>,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Graph<T> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Graph {
+            nodes: RefCell::new(Vec::with_capacity(capacity)),
+            generation: Cell::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.borrow().is_empty()
+    }
+
+    /// Drop every node created after the first `len`. Using a `Var` that refers to a dropped node panics.
+    pub fn truncate(&self, len: usize) {
+        self.nodes.borrow_mut().truncate(len);
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    pub fn var(&self, data: T) -> Var<'_, T> {
+        self.push(data, ArenaOp::Leaf)
+    }
+
+    pub fn var_with_label(&self, data: T, label: &str) -> Var<'_, T> {
+        let var = self.var(data);
+        var.set_label(label);
+        var
+    }
+
+    fn push(&self, data: T, op: ArenaOp<T>) -> Var<'_, T> {
+        let mut nodes = self.nodes.borrow_mut();
+        let generation = self.generation.get();
+        nodes.push(Node { data, grad: T::zero(), op, generation, label: String::new(), hooks: Vec::new() });
+        if is_anomaly_detection_enabled() && !data.is_finite() {
+            let problem = if matches!(op, ArenaOp::Leaf) { "created with" } else { "forward produced" };
+            report_anomaly(&nodes, nodes.len() - 1, &format!("{} {}", problem, data));
+        }
+        Var { graph: self, index: nodes.len() - 1, generation }
+    }
+}
+
+// Like `Scalar`'s anomaly reports, but unlabelled nodes are identified by index
+fn report_anomaly<T: Display>(nodes: &[Node<T>], index: usize, problem: &str) -> ! {
+    let describe = |i: usize| format!("{} {{ data: {:.4}, grad: {:.4} }}", nodes[i].name(i), nodes[i].data, nodes[i].grad);
+    let producers: Vec<String> = nodes[index].op.operands().into_iter().map(describe).collect();
+    let message = format!("Anomaly detected: {} at {} (op: {}), producers: [{}]",
+        problem, nodes[index].name(index), nodes[index].op, producers.join(", "));
+    log::error!("{}", message);
+    panic!("{}", message);
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Default for Graph<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A handle to a node in a `Graph`, with the same API as `Scalar`
+#[derive(Clone, Copy)]
+pub struct Var<'g, T> {
+    graph: &'g Graph<T>,
+    index: usize,
+    generation: u64,
+}
+
+impl<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static> Var<'g, T> {
+    // Run `f` on this Var's node, checking that `truncate` hasn't dropped it
+    fn with_node<R>(&self, f: impl FnOnce(&mut Node<T>) -> R) -> R {
+        let mut nodes = self.graph.nodes.borrow_mut();
+        match nodes.get_mut(self.index) {
+            Some(node) if node.generation == self.generation => f(node),
+            _ => panic!("Var #{} refers to a node that Graph::truncate dropped", self.index),
+        }
+    }
+
+    // The index of `rhs`, to combine it with this Var. An index only means something in its own graph.
+    fn operand(&self, rhs: &Var<'g, T>) -> usize {
+        assert!(ptr::eq(self.graph, rhs.graph), "Can't combine Vars from different Graphs");
+        rhs.with_node(|_| rhs.index)
+    }
+
+    pub fn get_data(&self) -> T {
+        self.with_node(|node| node.data)
+    }
+
+    pub fn get_grad(&self) -> T {
+        self.with_node(|node| node.grad)
+    }
+
+    pub fn zero_grad(&self) {
+        self.with_node(|node| node.grad = T::zero());
+    }
+
+    pub fn add_to_data(&self, diff: T) {
+        self.with_node(|node| node.data += diff);
+    }
+
+    pub fn set_label(&self, label: &str) {
+        self.with_node(|node| node.label = label.to_owned());
+    }
+
+    pub fn get_label(&self) -> String {
+        self.with_node(|node| node.label.clone())
+    }
+
+    /// Like `Scalar::register_hook`: `hook` is called with the gradient arriving at this node during each `backward`
+    /// and returns the gradient to use instead
+    pub fn register_hook<F: Fn(T) -> T + 'static>(&self, hook: F) {
+        self.with_node(|node| node.hooks.push(Box::new(hook)));
+    }
+
+    pub fn clear_hooks(&self) {
+        self.with_node(|node| node.hooks.clear());
+    }
+
+    pub fn backward(&self) {
+        self.backward_with(T::one());
+    }
+
+    /// Backpropagate from this node, seeding its gradient with `seed` rather than one, like `Scalar::backward_with`
+    pub fn backward_with(&self, seed: T) {
+        self.with_node(|_| ());
+        let mut nodes = self.graph.nodes.borrow_mut();
+
+        // As with Scalar, interior gradients are recomputed on every pass and leaf gradients accumulate. Leaves with
+        // hooks, and a leaf root, start the pass at zero and get their earlier gradient back at the end, so that
+        // hooks only see the gradient from this pass.
+        let mut earlier_grads = Vec::new();
+        for (i, node) in nodes[..=self.index].iter_mut().enumerate() {
+            if matches!(node.op, ArenaOp::Leaf) {
+                if node.hooks.is_empty() && i != self.index {
+                    continue;
+                }
+                earlier_grads.push((i, node.grad));
+            }
+            node.grad = T::zero();
+        }
+
+        nodes[self.index].grad = seed;
+        let detect_anomaly = is_anomaly_detection_enabled();
+        for i in (0..=self.index).rev() {
+            // Hooks are 'static, so they can't hold a Var and use the graph while it's borrowed
+            let node = &mut nodes[i];
+            node.grad = node.hooks.iter().fold(node.grad, |grad, hook| hook(grad));
+
+            let out_data = nodes[i].data;
+            let out_grad = nodes[i].grad;
+            match nodes[i].op {
+                ArenaOp::Leaf => {}
+                ArenaOp::Add(a, b) => {
+                    nodes[a].grad += out_grad;
+                    nodes[b].grad += out_grad;
+                }
+                ArenaOp::Mul(a, b) => {
+                    let (a_data, b_data) = (nodes[a].data, nodes[b].data);
+                    nodes[a].grad += b_data * out_grad;
+                    nodes[b].grad += a_data * out_grad;
+                }
+                ArenaOp::Pow(a, power) => {
+                    let a_data = nodes[a].data;
+                    nodes[a].grad += power * a_data.powf(power - T::one()) * out_grad;
+                }
+                ArenaOp::Exp(a) => nodes[a].grad += out_data * out_grad,
+                ArenaOp::Tanh(a) => nodes[a].grad += (T::one() - out_data * out_data) * out_grad,
+                ArenaOp::Relu(a) => {
+                    if out_data > T::zero() {
+                        nodes[a].grad += out_grad;
+                    }
+                }
+            }
+            if detect_anomaly {
+                if let Some(a) = nodes[i].op.operands().into_iter().find(|a| !nodes[*a].grad.is_finite()) {
+                    let problem = format!("backward produced gradient {} for {}", nodes[a].grad, nodes[a].name(a));
+                    report_anomaly(&nodes, i, &problem);
+                }
+            }
+        }
+        for (i, grad) in earlier_grads {
+            nodes[i].grad += grad;
+        }
+    }
+
+    pub fn exp(&self) -> Self {
+        self.graph.push(self.get_data().exp(), ArenaOp::Exp(self.index))
+    }
+
+    pub fn tanh(&self) -> Self {
+        self.graph.push(self.get_data().tanh(), ArenaOp::Tanh(self.index))
+    }
+
+    pub fn relu(&self) -> Self {
+        self.graph.push(self.get_data().max(T::zero()), ArenaOp::Relu(self.index))
+    }
+
+    pub fn pow(&self, power: T) -> Self {
+        self.graph.push(self.get_data().powf(power), ArenaOp::Pow(self.index, power))
+    }
+
+    pub fn add_number(&self, number: T) -> Self {
+        self + &self.graph.var(number)
+    }
+
+    pub fn mul_number(&self, number: T) -> Self {
+        self * &self.graph.var(number)
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Display for Var<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let (name, data, grad) = self.with_node(|node| (node.name(self.index), node.data, node.grad));
+        write!(f, "{} {{ data: {:.4}, grad: {:.4} }}", name, data, grad)
+    }
+}
+
+// Operator Overides ----------------------------------------------------------
+
+impl<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static> Add<&Var<'g, T>> for &Var<'g, T> {
+    type Output = Var<'g, T>;
+
+    fn add(self, rhs: &Var<'g, T>) -> Var<'g, T> {
+        let rhs_index = self.operand(rhs);
+        let data = self.get_data() + rhs.get_data();
+        self.graph.push(data, ArenaOp::Add(self.index, rhs_index))
+    }
+}
+
+impl<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static> Div<&Var<'g, T>> for &Var<'g, T> {
+    type Output = Var<'g, T>;
+
+    fn div(self, rhs: &Var<'g, T>) -> Var<'g, T> {
+        self * &rhs.pow(T::from(-1).unwrap())
+    }
+}
+
+impl<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static> Mul<&Var<'g, T>> for &Var<'g, T> {
+    type Output = Var<'g, T>;
+
+    fn mul(self, rhs: &Var<'g, T>) -> Var<'g, T> {
+        let rhs_index = self.operand(rhs);
+        let data = self.get_data() * rhs.get_data();
+        self.graph.push(data, ArenaOp::Mul(self.index, rhs_index))
+    }
+}
+
+impl<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static> Neg for &Var<'g, T> {
+    type Output = Var<'g, T>;
+
+    fn neg(self) -> Var<'g, T> {
+        self.mul_number(T::from(-1).unwrap())
+    }
+}
+
+impl<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static> Sub<&Var<'g, T>> for &Var<'g, T> {
+    type Output = Var<'g, T>;
+
+    fn sub(self, rhs: &Var<'g, T>) -> Var<'g, T> {
+        self + &(-rhs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scalar;
+
+    #[test]
+    fn gradients_match_scalar() {
+        let scalar_x = Scalar::new(0.5);
+        let scalar_y = Scalar::new(-1.5);
+        let scalar_out = (&(&scalar_x * &scalar_y).tanh() - &scalar_x.exp().pow(2.0)).relu().add_number(1.0);
+        scalar_out.backward_with(0.5);
+
+        let graph = Graph::new();
+        let x = graph.var(0.5);
+        let y = graph.var(-1.5);
+        let out = (&(&x * &y).tanh() - &x.exp().pow(2.0)).relu().add_number(1.0);
+        out.backward_with(0.5);
+
+        assert_eq!(out.get_data(), scalar_out.get_data());
+        assert_eq!(x.get_grad(), scalar_x.get_grad());
+        assert_eq!(y.get_grad(), scalar_y.get_grad());
+    }
+
+    #[test]
+    fn leaf_gradients_accumulate_and_hooks_see_each_pass() {
+        let graph = Graph::new();
+        let x = graph.var_with_label(3.0, "x");
+        x.register_hook(|grad: f64| grad.clamp(-1.0, 1.0));
+        let square = x.pow(2.0);
+        square.backward();
+        square.backward();
+        assert_eq!(x.get_grad(), 2.0);
+        assert_eq!(square.get_grad(), 1.0);
+        assert_eq!(x.to_string(), "'x' { data: 3.0000, grad: 2.0000 }");
+    }
+
+    #[test]
+    fn hooks_run_in_order_and_replace_interior_gradients() {
+        let graph = Graph::new();
+        let x = graph.var(3.0);
+        let square = x.pow(2.0);
+        let double = square.mul_number(2.0);
+        square.register_hook(|grad| grad + 1.0);
+        square.register_hook(|grad| -grad);
+        double.backward();
+        assert_eq!(square.get_grad(), -3.0);
+        assert_eq!(x.get_grad(), -18.0);
+        square.clear_hooks();
+        double.backward();
+        assert_eq!(x.get_grad(), -6.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Var #1 refers to a node that Graph::truncate dropped")]
+    fn vars_dropped_by_truncate_panic_even_if_their_index_is_reused() {
+        let graph = Graph::new();
+        let x = graph.var(3.0);
+        let stale = x.pow(2.0);
+        graph.truncate(1);
+        let _newer = x.exp();
+        stale.get_data();
+    }
+
+    #[test]
+    fn vars_kept_by_truncate_still_work() {
+        let graph = Graph::new();
+        let x = graph.var(3.0);
+        x.pow(2.0).backward();
+        graph.truncate(1);
+        x.mul_number(2.0).backward();
+        assert_eq!(x.get_grad(), 8.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't combine Vars from different Graphs")]
+    fn vars_from_different_graphs_panic() {
+        let first = Graph::new();
+        let second = Graph::new();
+        let _ = &first.var(1.0) + &second.var(2.0);
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use num_traits::Float;
+
+use crate::nn::NnError;
+use crate::optim::{Adam, AdamState};
+use crate::{Module, Scalar};
+
+const MAGIC: &[u8; 4] = b"MGCK";
+
+/// Bump this when the binary layout changes
+pub const CHECKPOINT_VERSION: u32 = 3;
+
+// Layout, all little-endian:
+//   magic "MGCK", version: u32, dtype: u8 (4 = f32, 8 = f64), step: u64, epoch: u64
+//   num_parameters: u64, then for each parameter: name length: u32, UTF-8 name, value
+//   has_optimizer: u8, then if 1: learning_rate, beta1, beta2, epsilon, num_steps: u64, then for each parameter, in
+//   the order above: has_moments: u8, then if 1: m, v
+// Values are stored in the model's own float type, so they are read back with exactly the same bits. Names are only
+// stored once, in the parameter table.
+
+/// A snapshot of training: the model's parameters by name, the optimizer's state and how far training has got
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoint<T> {
+    pub parameters: Vec<(String, T)>,
+    pub optimizer: Option<OptimizerCheckpoint<T>>,
+    pub step: u64,
+    pub epoch: u64,
+}
+
+/// The optimizer's state in a checkpoint. Each parameter's moments are keyed by the parameter's name in the model,
+/// so restoring doesn't depend on the order the optimizer holds its parameters in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptimizerCheckpoint<T> {
+    pub learning_rate: T,
+    pub beta1: T,
+    pub beta2: T,
+    pub epsilon: T,
+    pub num_steps: u64,
+    /// Name, m and v of each parameter the optimizer updates, in the order of the checkpoint's parameters
+    pub moments: Vec<(String, T, T)>,
+}
+
+// The model's name for each of the optimizer's parameters, in the optimizer's order
+fn optimizer_names<T, M>(model: &M, parameters: &[Scalar<T>]) -> Result<Vec<String>, NnError>
+where
+    T: Float + Copy + Display + std::ops::AddAssign + 'static,
+    M: Module<T> + ?Sized,
+{
+    let names: HashMap<usize, String> = model.named_parameters().into_iter().map(|(name, p)| (p.id(), name)).collect();
+    parameters.iter().map(|p| names.get(&p.id()).cloned().ok_or_else(|| {
+        NnError::InvalidConfig(format!("The optimizer updates {}, which isn't one of the model's parameters",
+                                       p.get_label()))
+    })).collect()
+}
+
+// The size in bytes of T, which is also its dtype tag
+fn dtype<T: Float>() -> Result<u8, NnError> {
+    match std::mem::size_of::<T>() {
+        4 => Ok(4),
+        8 => Ok(8),
+        size => Err(NnError::InvalidFormat(format!("Only f32 and f64 can be checkpointed, not {}-byte floats", size))),
+    }
+}
+
+fn dtype_name(dtype: u8) -> String {
+    match dtype {
+        4 => "f32".to_string(),
+        8 => "f64".to_string(),
+        _ => format!("unknown dtype {}", dtype),
+    }
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+fn write_value<T: Float>(bytes: &mut Vec<u8>, value: T) {
+    // Casting an f32 to f64 and back is exact
+    let value = value.to_f64().unwrap();
+    if std::mem::size_of::<T>() == 4 {
+        bytes.extend_from_slice(&(value as f32).to_le_bytes());
+    } else {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+// Reads the fields of a checkpoint in order, failing instead of running off the end
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], NnError> {
+        let end = self.position.checked_add(count).filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| NnError::InvalidFormat(format!("Checkpoint ends early, at byte {}", self.bytes.len())))?;
+        let taken = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(taken)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, NnError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, NnError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, NnError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn read_name(&mut self) -> Result<String, NnError> {
+        let length = self.read_u32()? as usize;
+        String::from_utf8(self.take(length)?.to_vec())
+            .map_err(|e| NnError::InvalidFormat(format!("Parameter name isn't UTF-8: {}", e)))
+    }
+
+    fn read_value<T: Float>(&mut self) -> Result<T, NnError> {
+        let value = if std::mem::size_of::<T>() == 4 {
+            f32::from_le_bytes(self.take(4)?.try_into().unwrap()) as f64
+        } else {
+            f64::from_le_bytes(self.take(8)?.try_into().unwrap())
+        };
+        Ok(T::from(value).unwrap())
+    }
+
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> OptimizerCheckpoint<T> {
+    // The state to load into `optimizer`, with the moments put in the order of its parameters
+    fn state_for<M: Module<T> + ?Sized>(&self, model: &M, optimizer: &Adam<T>) -> Result<AdamState<T>, NnError> {
+        let names = optimizer_names(model, optimizer.parameters())?;
+        let moments: HashMap<&str, (T, T)> = self.moments.iter().map(|(name, m, v)| (name.as_str(), (*m, *v))).collect();
+        let missing: Vec<String> = names.iter().filter(|name| !moments.contains_key(name.as_str())).cloned().collect();
+        let mut unexpected: Vec<String> = self.moments.iter().map(|(name, _, _)| name)
+            .filter(|name| !names.contains(name))
+            .cloned()
+            .collect();
+        unexpected.sort();
+        if !(missing.is_empty() && unexpected.is_empty()) {
+            Err(NnError::StateDictMismatch { missing, unexpected })?
+        }
+        Ok(AdamState {
+            learning_rate: self.learning_rate,
+            beta1: self.beta1,
+            beta2: self.beta2,
+            epsilon: self.epsilon,
+            num_steps: self.num_steps,
+            m: names.iter().map(|name| moments[name.as_str()].0).collect(),
+            v: names.iter().map(|name| moments[name.as_str()].1).collect(),
+        })
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Checkpoint<T> {
+    /// Snapshot `model` and, if there is one, the optimizer training it. Every parameter the optimizer updates must
+    /// belong to `model`.
+    pub fn new<M: Module<T> + ?Sized>(model: &M, optimizer: Option<&Adam<T>>, step: u64, epoch: u64)
+        -> Result<Self, NnError> {
+        let named_parameters = model.named_parameters();
+        let optimizer = optimizer.map(|optimizer| {
+            let state = optimizer.state();
+            let moments: HashMap<String, (T, T)> = optimizer_names(model, optimizer.parameters())?.into_iter()
+                .zip(state.m.into_iter().zip(state.v))
+                .collect();
+            Ok::<_, NnError>(OptimizerCheckpoint {
+                learning_rate: state.learning_rate,
+                beta1: state.beta1,
+                beta2: state.beta2,
+                epsilon: state.epsilon,
+                num_steps: state.num_steps,
+                moments: named_parameters.iter()
+                    .filter_map(|(name, _)| moments.get(name).map(|(m, v)| (name.clone(), *m, *v)))
+                    .collect(),
+            })
+        }).transpose()?;
+        Ok(Checkpoint {
+            parameters: named_parameters.into_iter().map(|(name, p)| (name, p.get_data())).collect(),
+            optimizer,
+            step,
+            epoch,
+        })
+    }
+
+    /// Put the snapshot back into `model` and `optimizer`. The parameter names must match exactly, for the model and
+    /// for the parameters the optimizer updates. Everything is checked before anything is changed, so on an error
+    /// neither the model nor the optimizer is touched.
+    pub fn restore<M: Module<T> + ?Sized>(&self, model: &M, optimizer: Option<&mut Adam<T>>) -> Result<(), NnError> {
+        let optimizer_state = match (&optimizer, &self.optimizer) {
+            (Some(optimizer), Some(checkpoint)) => Some(checkpoint.state_for(model, optimizer)?),
+            (Some(_), None) => Err(NnError::InvalidFormat("The checkpoint has no optimizer state".to_string()))?,
+            (None, _) => None,
+        };
+        // A strict load checks every name before loading anything
+        let state: HashMap<String, T> = self.parameters.iter().cloned().collect();
+        model.load_state_dict(&state, true)?;
+        if let (Some(optimizer), Some(optimizer_state)) = (optimizer, optimizer_state) {
+            optimizer.load_state(&optimizer_state)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, NnError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+        bytes.push(dtype::<T>()?);
+        bytes.extend_from_slice(&self.step.to_le_bytes());
+        bytes.extend_from_slice(&self.epoch.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.parameters.len() as u64).to_le_bytes());
+        for (name, value) in &self.parameters {
+            write_name(&mut bytes, name);
+            write_value(&mut bytes, *value);
+        }
+
+        match &self.optimizer {
+            Some(optimizer) => {
+                bytes.push(1);
+                for value in [optimizer.learning_rate, optimizer.beta1, optimizer.beta2, optimizer.epsilon] {
+                    write_value(&mut bytes, value);
+                }
+                bytes.extend_from_slice(&optimizer.num_steps.to_le_bytes());
+                let names: HashSet<&str> = self.parameters.iter().map(|(name, _)| name.as_str()).collect();
+                let mut moments: HashMap<&str, (T, T)> = HashMap::new();
+                for (name, m, v) in &optimizer.moments {
+                    if !names.contains(name.as_str()) || moments.insert(name, (*m, *v)).is_some() {
+                        Err(NnError::InvalidConfig(format!("The optimizer has moments for {}, which isn't a parameter, \
+                                                            or has two sets of them", name)))?
+                    }
+                }
+                for (name, _) in &self.parameters {
+                    match moments.get(name.as_str()) {
+                        Some((m, v)) => {
+                            bytes.push(1);
+                            write_value(&mut bytes, *m);
+                            write_value(&mut bytes, *v);
+                        }
+                        None => bytes.push(0),
+                    }
+                }
+            }
+            None => bytes.push(0),
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, NnError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            Err(NnError::InvalidFormat("Not a checkpoint: the magic number is wrong".to_string()))?
+        }
+        let version = reader.read_u32()?;
+        if version != CHECKPOINT_VERSION {
+            Err(NnError::InvalidFormat(format!("Unsupported version {}, expected {}", version, CHECKPOINT_VERSION)))?
+        }
+        let stored_dtype = reader.read_u8()?;
+        if stored_dtype != dtype::<T>()? {
+            Err(NnError::InvalidFormat(format!("The checkpoint holds {} values, but the model uses {}",
+                                               dtype_name(stored_dtype), dtype_name(dtype::<T>()?))))?
+        }
+        let step = reader.read_u64()?;
+        let epoch = reader.read_u64()?;
+
+        let num_parameters = reader.read_u64()? as usize;
+        let mut parameters = Vec::new();
+        for _ in 0..num_parameters {
+            parameters.push((reader.read_name()?, reader.read_value()?));
+        }
+
+        let optimizer = match reader.read_u8()? {
+            0 => None,
+            1 => Some(OptimizerCheckpoint {
+                learning_rate: reader.read_value()?,
+                beta1: reader.read_value()?,
+                beta2: reader.read_value()?,
+                epsilon: reader.read_value()?,
+                num_steps: reader.read_u64()?,
+                moments: {
+                    let mut moments = Vec::new();
+                    for (name, _) in &parameters {
+                        match reader.read_u8()? {
+                            0 => (),
+                            1 => moments.push((name.clone(), reader.read_value()?, reader.read_value()?)),
+                            flag => Err(NnError::InvalidFormat(format!("Invalid moments flag {} for {}", flag, name)))?,
+                        }
+                    }
+                    moments
+                },
+            }),
+            flag => Err(NnError::InvalidFormat(format!("Invalid optimizer flag {}", flag)))?,
+        };
+        if reader.position != bytes.len() {
+            Err(NnError::InvalidFormat(format!("{} unexpected bytes after the checkpoint", bytes.len() - reader.position)))?
+        }
+        Ok(Checkpoint { parameters, optimizer, step, epoch })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), NnError> {
+        fs::write(path, self.to_bytes()?)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, NnError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MultiLayerPerceptron;
+
+    fn new_model(seed: u64) -> MultiLayerPerceptron<f64> {
+        MultiLayerPerceptron::builder(3).layers(&[4, 1]).seed(seed).build().unwrap()
+    }
+
+    fn train_step(mlp: &MultiLayerPerceptron<f64>, optimizer: &mut Adam<f64>) {
+        let xs = [vec![2.0, 3.0, -1.0], vec![3.0, -1.0, 0.5], vec![0.5, 1.0, 1.0]];
+        let ys = [1.0, -1.0, 1.0];
+        let mut loss = Scalar::new(0.0);
+        for (prediction, y) in mlp.forward_batch(&xs).unwrap().concat().iter().zip(ys) {
+            loss = &loss + &prediction.add_number(-y).pow(2.0);
+        }
+        optimizer.zero_grad();
+        loss.backward();
+        optimizer.step();
+    }
+
+    fn bits(mlp: &MultiLayerPerceptron<f64>) -> Vec<u64> {
+        mlp.parameters().iter().map(|p| p.get_data().to_bits()).collect()
+    }
+
+    #[test]
+    fn resumed_training_matches_uninterrupted_training() {
+        let mlp = new_model(1);
+        let mut optimizer = Adam::new(mlp.parameters(), 0.05);
+        for _ in 0..10 {
+            train_step(&mlp, &mut optimizer);
+        }
+
+        let stopped = new_model(1);
+        let mut stopped_optimizer = Adam::new(stopped.parameters(), 0.05);
+        for _ in 0..5 {
+            train_step(&stopped, &mut stopped_optimizer);
+        }
+        let checkpoint = Checkpoint::new(&stopped, Some(&stopped_optimizer), 5, 0).unwrap();
+        let bytes = checkpoint.to_bytes().unwrap();
+        assert_eq!(Checkpoint::from_bytes(&bytes).unwrap(), checkpoint);
+
+        // The moments follow the names, so an optimizer holding the parameters in another order still resumes
+        let resumed = new_model(2);
+        let mut reversed = resumed.parameters();
+        reversed.reverse();
+        let mut resumed_optimizer = Adam::new(reversed, 0.001);
+        Checkpoint::from_bytes(&bytes).unwrap().restore(&resumed, Some(&mut resumed_optimizer)).unwrap();
+        assert_eq!(resumed_optimizer.num_steps(), 5);
+        for _ in 0..5 {
+            train_step(&resumed, &mut resumed_optimizer);
+        }
+        assert_eq!(bits(&resumed), bits(&mlp));
+    }
+
+    #[test]
+    fn failed_restores_change_nothing() {
+        let mlp = new_model(1);
+        let optimizer = Adam::new(mlp.parameters(), 0.05);
+        let without_optimizer = Checkpoint::new(&mlp, None, 0, 0).unwrap();
+        let mut with_optimizer = Checkpoint::new(&mlp, Some(&optimizer), 0, 0).unwrap();
+        with_optimizer.optimizer.as_mut().unwrap().moments.pop();
+
+        let other = new_model(2);
+        let original = bits(&other);
+        let mut other_optimizer = Adam::new(other.parameters(), 0.001);
+        assert!(matches!(without_optimizer.restore(&other, Some(&mut other_optimizer)),
+                         Err(NnError::InvalidFormat(_))));
+        assert!(matches!(with_optimizer.restore(&other, Some(&mut other_optimizer)),
+                         Err(NnError::StateDictMismatch { missing, .. }) if missing == ["layers.1.neurons.0.b"]));
+        assert_eq!(bits(&other), original);
+        assert_eq!(other_optimizer.learning_rate, 0.001);
+
+        // Each parameter has a flag saying whether its moments follow
+        let checkpoint = Checkpoint::new(&mlp, Some(&optimizer), 0, 0).unwrap();
+        let bytes = checkpoint.to_bytes().unwrap();
+        let first_flag = bytes.len() - checkpoint.parameters.len() * 17;
+        let mut bad_flag = bytes.clone();
+        bad_flag[first_flag] = 2;
+        assert!(matches!(Checkpoint::<f64>::from_bytes(&bad_flag),
+                         Err(NnError::InvalidFormat(message)) if message.contains("flag 2 for layers.0.neurons.0.w0")));
+        assert!(matches!(Checkpoint::<f64>::from_bytes(&bytes[..bytes.len() - 1]), Err(NnError::InvalidFormat(_))));
+        let mut skipped = bytes[..first_flag].to_vec();
+        skipped.push(0);
+        skipped.extend_from_slice(&bytes[first_flag + 17..]);
+        let restored = Checkpoint::<f64>::from_bytes(&skipped).unwrap();
+        assert_eq!(restored.optimizer.unwrap().moments[..], checkpoint.optimizer.as_ref().unwrap().moments[1..]);
+
+        let mut unknown = checkpoint.clone();
+        unknown.optimizer.as_mut().unwrap().moments[0].0 = "layers.9.neurons.0.b".to_string();
+        assert!(matches!(unknown.to_bytes(), Err(NnError::InvalidConfig(_))));
+        let mut repeated = checkpoint;
+        let moments = &mut repeated.optimizer.as_mut().unwrap().moments;
+        moments[1].0 = moments[0].0.clone();
+        assert!(matches!(repeated.to_bytes(), Err(NnError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn names_are_stored_once() {
+        let mlp = new_model(1);
+        let optimizer = Adam::new(mlp.parameters(), 0.05);
+        let bytes = Checkpoint::new(&mlp, Some(&optimizer), 0, 0).unwrap().to_bytes().unwrap();
+        let names: usize = mlp.named_parameters().iter().map(|(name, _)| 4 + name.len()).sum();
+        let num_parameters = mlp.parameters().len();
+        // The header and parameter count, a name and value per parameter, then the optimizer's flag, settings and
+        // step count, and a flag, m and v per parameter
+        assert_eq!(bytes.len(), 25 + 8 + names + 8 * num_parameters + 1 + 32 + 8 + 17 * num_parameters);
+        assert!(bytes.len() < mlp.to_json().unwrap().len());
+    }
+
+    #[test]
+    fn optimizers_must_train_the_checkpointed_model() {
+        let mlp = new_model(1);
+        let optimizer = Adam::new(new_model(2).parameters(), 0.05);
+        assert!(matches!(Checkpoint::new(&mlp, Some(&optimizer), 0, 0), Err(NnError::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,481 @@
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use num_traits::Float;
+
+use crate::nn::NnError;
+use crate::MultiLayerPerceptron;
+
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+fn invalid(message: String) -> NnError {
+    NnError::InvalidFormat(message)
+}
+
+// Array ----------------------------------------------------------------------
+
+// The number of values in an array of `shape`, which a corrupt file can make too large to count
+fn num_elements(shape: &[usize]) -> Result<usize, NnError> {
+    shape.iter().try_fold(1usize, |count, d| count.checked_mul(*d))
+        .ok_or_else(|| invalid(format!("Shape {:?} has too many elements", shape)))
+}
+
+/// An n-dimensional array of numbers in C (row-major) order, as stored in a `.npy` file
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray<T> {
+    pub shape: Vec<usize>,
+    pub data: Vec<T>,
+}
+
+impl<T: Float> NpyArray<T> {
+    pub fn new(shape: Vec<usize>, data: Vec<T>) -> Result<Self, NnError> {
+        let num_elements = num_elements(&shape)?;
+        if num_elements != data.len() {
+            Err(NnError::ShapeMismatch { expected: num_elements, actual: data.len() })?
+        }
+        Ok(NpyArray { shape, data })
+    }
+
+    /// A 2-D array with one row per sample, e.g. for `MultiLayerPerceptron::predict_batch`
+    pub fn from_rows(rows: &[Vec<T>]) -> Result<Self, NnError> {
+        let num_columns = rows.first().map_or(0, |row| row.len());
+        if let Some(row) = rows.iter().find(|row| row.len() != num_columns) {
+            Err(NnError::ShapeMismatch { expected: num_columns, actual: row.len() })?
+        }
+        Ok(NpyArray { shape: vec![rows.len(), num_columns], data: rows.concat() })
+    }
+
+    /// The rows of a 2-D array, e.g. the samples of a dataset for `MultiLayerPerceptron::forward_batch`
+    pub fn rows(&self) -> Result<Vec<Vec<T>>, NnError> {
+        match self.shape[..] {
+            [_, num_columns] if num_columns > 0 => Ok(self.data.chunks(num_columns).map(|row| row.to_vec()).collect()),
+            [num_rows, _] => Ok(vec![Vec::new(); num_rows]),
+            _ => Err(invalid(format!("Expected a 2-D array, not shape {:?}", self.shape))),
+        }
+    }
+}
+
+// NPY ------------------------------------------------------------------------
+
+// The value of `key` in a header dict like {'descr': '<f8', 'fortran_order': False, 'shape': (3, 4), }
+fn header_value<'h>(header: &'h str, key: &str) -> Result<&'h str, NnError> {
+    let start = [format!("'{}'", key), format!("\"{}\"", key)].iter()
+        .find_map(|quoted| header.find(quoted.as_str()).map(|i| i + quoted.len()))
+        .ok_or_else(|| invalid(format!("NPY header has no {}", key)))?;
+    let rest = header[start..].trim_start();
+    let rest = rest.strip_prefix(':').ok_or_else(|| invalid(format!("NPY header has no value for {}", key)))?;
+    Ok(rest.trim_start())
+}
+
+fn parse_npy_header(header: &str) -> Result<(usize, Vec<usize>), NnError> {
+    let descr = header_value(header, "descr")?;
+    let quote = descr.chars().next().filter(|c| *c == '\'' || *c == '"')
+        .ok_or_else(|| invalid("NPY descr isn't a string".to_string()))?;
+    let descr = descr[1..].split(quote).next().unwrap_or("");
+    let element_size = match descr {
+        "<f4" => 4,
+        "<f8" => 8,
+        _ => Err(invalid(format!("Unsupported dtype {}, expected little-endian float32 or float64", descr)))?,
+    };
+
+    if !header_value(header, "fortran_order")?.starts_with("False") {
+        Err(invalid("Fortran-order arrays aren't supported".to_string()))?
+    }
+
+    let shape = header_value(header, "shape")?;
+    let end = shape.find(')').filter(|_| shape.starts_with('('))
+        .ok_or_else(|| invalid("NPY shape isn't a tuple".to_string()))?;
+    let shape = shape[1..end].split(',').map(str::trim).filter(|d| !d.is_empty())
+        .map(|d| d.parse::<usize>().map_err(|_| invalid(format!("Invalid dimension {} in NPY shape", d))))
+        .collect::<Result<_, _>>()?;
+    Ok((element_size, shape))
+}
+
+/// Parse a `.npy` file of float32 or float64 values. float32 files load exactly into f64 arrays; float64 files are
+/// rounded to the nearest f32 for f32 arrays, and are an error if a value is too large for an f32.
+pub fn read_npy<T: Float>(bytes: &[u8]) -> Result<NpyArray<T>, NnError> {
+    if !bytes.starts_with(NPY_MAGIC) || bytes.len() < 10 {
+        Err(invalid("Not an NPY file: the magic string is wrong".to_string()))?
+    }
+    let (header_start, header_length) = match bytes[6] {
+        1 => (10, u16::from_le_bytes([bytes[8], bytes[9]]) as usize),
+        2 | 3 if bytes.len() >= 12 => (12, u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize),
+        version => Err(invalid(format!("Unsupported NPY version {}", version)))?,
+    };
+    let data_start = header_start + header_length;
+    let header = bytes.get(header_start..data_start)
+        .ok_or_else(|| invalid("NPY header is longer than the file".to_string()))?;
+    let header = std::str::from_utf8(header).map_err(|e| invalid(format!("NPY header isn't text: {}", e)))?;
+    let (element_size, shape) = parse_npy_header(header)?;
+
+    let num_bytes = num_elements(&shape)?.checked_mul(element_size)
+        .ok_or_else(|| invalid(format!("NPY shape {:?} has too many elements", shape)))?;
+    let data = &bytes[data_start..];
+    if data.len() != num_bytes {
+        Err(invalid(format!("NPY shape {:?} needs {} bytes of data, not {}", shape, num_bytes, data.len())))?
+    }
+    let data = data.chunks_exact(element_size).map(|chunk| {
+        let value = if element_size == 4 {
+            f32::from_le_bytes(chunk.try_into().unwrap()) as f64
+        } else {
+            f64::from_le_bytes(chunk.try_into().unwrap())
+        };
+        // An f64 too large for an f32 array would otherwise become infinite
+        T::from(value).filter(|v| v.is_finite() || !value.is_finite())
+            .ok_or_else(|| invalid(format!("NPY value {} is out of range", value)))
+    }).collect::<Result<_, _>>()?;
+    Ok(NpyArray { shape, data })
+}
+
+/// Write `array` as a `.npy` file, in version 1.0 unless its header is too long for that
+pub fn write_npy<T: Float>(array: &NpyArray<T>) -> Result<Vec<u8>, NnError> {
+    let (descr, element_size) = match std::mem::size_of::<T>() {
+        4 => ("<f4", 4),
+        8 => ("<f8", 8),
+        size => Err(invalid(format!("Only f32 and f64 can be stored as NPY, not {}-byte floats", size)))?,
+    };
+    let shape = match array.shape.len() {
+        1 => format!("({},)", array.shape[0]),
+        _ => format!("({})", array.shape.iter().map(|d| d.to_string()).collect::<Vec<String>>().join(", ")),
+    };
+    let mut header = format!("{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}", descr, shape);
+
+    // The header ends in a newline and is padded with spaces so the data starts 64-byte aligned
+    let version: u8 = if header.len() + 64 <= u16::MAX as usize { 1 } else { 2 };
+    let prefix_length = if version == 1 { 10 } else { 12 };
+    let padded_length = (prefix_length + header.len() + 1).next_multiple_of(64) - prefix_length;
+    header.extend(std::iter::repeat_n(' ', padded_length - header.len() - 1));
+    header.push('\n');
+
+    let mut bytes = NPY_MAGIC.to_vec();
+    bytes.extend_from_slice(&[version, 0]);
+    if version == 1 {
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    } else {
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+    }
+    bytes.extend_from_slice(header.as_bytes());
+    bytes.reserve(array.data.len() * element_size);
+    for value in &array.data {
+        // Casting an f32 to f64 and back is exact
+        let value = value.to_f64().unwrap();
+        if element_size == 4 {
+            bytes.extend_from_slice(&(value as f32).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    Ok(bytes)
+}
+
+pub fn load_npy<T: Float, P: AsRef<Path>>(path: P) -> Result<NpyArray<T>, NnError> {
+    read_npy(&fs::read(path)?)
+}
+
+pub fn save_npy<T: Float, P: AsRef<Path>>(path: P, array: &NpyArray<T>) -> Result<(), NnError> {
+    fs::write(path, write_npy(array)?)?;
+    Ok(())
+}
+
+// NPZ ------------------------------------------------------------------------
+
+// An .npz file is a zip archive holding one .npy file per array. Only stored (uncompressed) entries are supported,
+// as written by `np.savez`, not `np.savez_compressed`.
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const ZIP64_EXTRA: u16 = 0x0001;
+// 1980-01-01, the earliest date a zip file can hold
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn u16_at(bytes: &[u8], position: usize) -> Result<u16, NnError> {
+    position.checked_add(2).and_then(|end| bytes.get(position..end)).map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("Zip archive ends early".to_string()))
+}
+
+fn u32_at(bytes: &[u8], position: usize) -> Result<u32, NnError> {
+    position.checked_add(4).and_then(|end| bytes.get(position..end)).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("Zip archive ends early".to_string()))
+}
+
+fn u64_at(bytes: &[u8], position: usize) -> Result<u64, NnError> {
+    position.checked_add(8).and_then(|end| bytes.get(position..end)).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| invalid("Zip archive ends early".to_string()))
+}
+
+/// Parse an uncompressed `.npz` archive into its named arrays, in archive order. Names don't include `.npy`.
+pub fn read_npz<T: Float>(bytes: &[u8]) -> Result<Vec<(String, NpyArray<T>)>, NnError> {
+    // The end of central directory record is at least 22 bytes, followed by a comment of up to 64 KiB
+    let end = (0..bytes.len().saturating_sub(21)).rev().take(u16::MAX as usize + 1)
+        .find(|i| u32_at(bytes, *i).is_ok_and(|signature| signature == END_OF_CENTRAL_DIRECTORY))
+        .ok_or_else(|| invalid("Not a zip archive: there is no end of central directory".to_string()))?;
+    let num_entries = u16_at(bytes, end + 10)? as usize;
+    let mut position = u32_at(bytes, end + 16)? as usize;
+
+    let mut arrays = Vec::new();
+    for _ in 0..num_entries {
+        if u32_at(bytes, position)? != CENTRAL_HEADER {
+            Err(invalid(format!("Expected a central directory entry at byte {}", position)))?
+        }
+        let method = u16_at(bytes, position + 10)?;
+        let crc = u32_at(bytes, position + 16)?;
+        let mut size = u32_at(bytes, position + 24)? as u64;
+        let name_length = u16_at(bytes, position + 28)? as usize;
+        let extra_length = u16_at(bytes, position + 30)? as usize;
+        let comment_length = u16_at(bytes, position + 32)? as usize;
+        let mut offset = u32_at(bytes, position + 42)? as u64;
+        let name_start = position + 46;
+        let name = bytes.get(name_start..name_start + name_length)
+            .and_then(|name| std::str::from_utf8(name).ok())
+            .ok_or_else(|| invalid("Zip entry name isn't UTF-8".to_string()))?
+            .to_string();
+
+        // Zip64 moves sizes and offsets that don't fit in 32 bits into an extra field, in this order
+        let mut extra = name_start + name_length;
+        while extra + 4 <= name_start + name_length + extra_length {
+            let (id, length) = (u16_at(bytes, extra)?, u16_at(bytes, extra + 2)? as usize);
+            if id == ZIP64_EXTRA {
+                let mut field = extra + 4;
+                if u32_at(bytes, position + 24)? == u32::MAX {
+                    size = u64_at(bytes, field)?;
+                    field += 8;
+                }
+                if u32_at(bytes, position + 20)? == u32::MAX {
+                    field += 8;
+                }
+                if u32_at(bytes, position + 42)? == u32::MAX {
+                    offset = u64_at(bytes, field)?;
+                }
+            }
+            extra += 4 + length;
+        }
+        position = name_start + name_length + extra_length + comment_length;
+
+        if method != 0 {
+            Err(invalid(format!("{} is compressed. Only uncompressed archives (np.savez) are supported", name)))?
+        }
+        let local = usize::try_from(offset).unwrap_or(usize::MAX);
+        if u32_at(bytes, local)? != LOCAL_HEADER {
+            Err(invalid(format!("Expected a local file header for {} at byte {}", name, local)))?
+        }
+        let data_start = local + 30 + u16_at(bytes, local + 26)? as usize + u16_at(bytes, local + 28)? as usize;
+        let data = usize::try_from(size).ok().and_then(|size| data_start.checked_add(size))
+            .and_then(|data_end| bytes.get(data_start..data_end))
+            .ok_or_else(|| invalid(format!("{} runs past the end of the archive", name)))?;
+        if crc32(data) != crc {
+            Err(invalid(format!("{} is corrupt: its CRC doesn't match", name)))?
+        }
+        let array = read_npy(data).map_err(|e| invalid(format!("{}: {}", name, e)))?;
+        arrays.push((name.strip_suffix(".npy").unwrap_or(&name).to_string(), array));
+    }
+    Ok(arrays)
+}
+
+/// Write named arrays as an uncompressed `.npz` archive, which `np.load` reads as a dict of arrays. Zip64 isn't
+/// written, so the archive can hold at most 65535 arrays and 4 GiB.
+pub fn write_npz<T: Float>(arrays: &[(String, NpyArray<T>)]) -> Result<Vec<u8>, NnError> {
+    let num_entries = u16::try_from(arrays.len())
+        .map_err(|_| invalid(format!("Archives of over {} arrays aren't supported, not {}", u16::MAX, arrays.len())))?;
+    let too_large = || invalid("Archives over 4 GiB aren't supported".to_string());
+    let mut bytes = Vec::new();
+    let mut central_directory = Vec::new();
+    for (name, array) in arrays {
+        let name = format!("{}.npy", name);
+        let data = write_npy(array)?;
+        let offset = u32::try_from(bytes.len()).map_err(|_| too_large())?;
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let name_length = u16::try_from(name.len()).map_err(|_| invalid(format!("Array name {} is too long", name)))?;
+        let crc = crc32(&data);
+
+        // The fields shared by the local header and the central directory entry, from "version needed" on
+        let mut fields = Vec::new();
+        fields.extend_from_slice(&20u16.to_le_bytes()); // Version needed to extract: 2.0
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Flags
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Method: stored
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Time
+        fields.extend_from_slice(&DOS_DATE.to_le_bytes());
+        fields.extend_from_slice(&crc.to_le_bytes());
+        fields.extend_from_slice(&size.to_le_bytes()); // Compressed size
+        fields.extend_from_slice(&size.to_le_bytes()); // Uncompressed size
+        fields.extend_from_slice(&name_length.to_le_bytes());
+        fields.extend_from_slice(&0u16.to_le_bytes()); // Extra field length
+
+        bytes.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        bytes.extend_from_slice(&fields);
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&data);
+
+        central_directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        central_directory.extend_from_slice(&20u16.to_le_bytes()); // Version made by
+        central_directory.extend_from_slice(&fields);
+        central_directory.extend_from_slice(&[0; 6]); // Comment length, disk number and internal attributes
+        central_directory.extend_from_slice(&[0; 4]); // External attributes
+        central_directory.extend_from_slice(&offset.to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+    }
+
+    // The end record holds the central directory's offset and size in 32 bits too
+    let central_directory_offset = u32::try_from(bytes.len()).map_err(|_| too_large())?;
+    let central_directory_size = u32::try_from(central_directory.len()).map_err(|_| too_large())?;
+    central_directory_offset.checked_add(central_directory_size).ok_or_else(too_large)?;
+    bytes.extend_from_slice(&central_directory);
+    bytes.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+    bytes.extend_from_slice(&[0; 4]); // This disk and the disk where the central directory starts
+    bytes.extend_from_slice(&num_entries.to_le_bytes()); // Entries on this disk
+    bytes.extend_from_slice(&num_entries.to_le_bytes()); // Total entries
+    bytes.extend_from_slice(&central_directory_size.to_le_bytes());
+    bytes.extend_from_slice(&central_directory_offset.to_le_bytes());
+    bytes.extend_from_slice(&0u16.to_le_bytes()); // Comment length
+    Ok(bytes)
+}
+
+pub fn load_npz<T: Float, P: AsRef<Path>>(path: P) -> Result<Vec<(String, NpyArray<T>)>, NnError> {
+    read_npz(&fs::read(path)?)
+}
+
+pub fn save_npz<T: Float, P: AsRef<Path>>(path: P, arrays: &[(String, NpyArray<T>)]) -> Result<(), NnError> {
+    fs::write(path, write_npz(arrays)?)?;
+    Ok(())
+}
+
+// MLP ------------------------------------------------------------------------
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> MultiLayerPerceptron<T> {
+    /// Each layer's weights as an [out, in] array named `layers.{i}.weight`, and its biases as `layers.{i}.bias`,
+    /// e.g. for `save_npz`. A layer without biases has no bias array. A layer where only some neurons have a bias
+    /// can't be written as arrays, so is an error.
+    pub fn weight_arrays(&self) -> Result<Vec<(String, NpyArray<T>)>, NnError> {
+        let mut arrays = Vec::new();
+        for (i, layer) in self.layers.iter().enumerate() {
+            let num_inputs = layer.neurons.first().map_or(0, |n| n.weights.len());
+            let weights = layer.neurons.iter().flat_map(|n| n.weights.iter().map(|w| w.get_data())).collect();
+            arrays.push((format!("layers.{}.weight", i),
+                         NpyArray { shape: vec![layer.neurons.len(), num_inputs], data: weights }));
+            let biases: Vec<T> = layer.neurons.iter().filter_map(|n| n.bias.as_ref().map(|b| b.get_data())).collect();
+            if biases.len() == layer.neurons.len() {
+                arrays.push((format!("layers.{}.bias", i), NpyArray { shape: vec![biases.len()], data: biases }));
+            } else if !biases.is_empty() {
+                Err(NnError::InvalidConfig(format!("Only {} of layer {}'s {} neurons have a bias",
+                                                   biases.len(), i, layer.neurons.len())))?
+            }
+        }
+        Ok(arrays)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written like np.save and np.savez would, see tests/fixtures/generate.py
+    const NUMPY_NPY: &[u8] = include_bytes!("../tests/fixtures/numpy_f8.npy");
+    const NUMPY_NPZ: &[u8] = include_bytes!("../tests/fixtures/numpy.npz");
+
+    #[test]
+    fn npy_round_trips() {
+        let array = NpyArray::new(vec![2, 3], vec![1.5, -2.0, 0.25, 3.0, 0.0, f64::MIN_POSITIVE]).unwrap();
+        let bytes = write_npy(&array).unwrap();
+        assert_eq!(bytes[6], 1);
+        assert_eq!(read_npy::<f64>(&bytes).unwrap(), array);
+
+        let array = NpyArray::new(vec![4], vec![0.1f32, -0.2, 1e30, 7.0]).unwrap();
+        assert_eq!(read_npy::<f32>(&write_npy(&array).unwrap()).unwrap(), array);
+    }
+
+    #[test]
+    fn npy_headers_too_long_for_version_1_use_version_2() {
+        let array = NpyArray::new(vec![1; 30000], vec![2.5f64]).unwrap();
+        let bytes = write_npy(&array).unwrap();
+        assert_eq!(bytes[6], 2);
+        let header_length = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        assert_eq!((12 + header_length) % 64, 0);
+        assert_eq!(read_npy::<f64>(&bytes).unwrap(), array);
+    }
+
+    #[test]
+    fn npz_round_trips() {
+        let arrays = vec![
+            ("layers.0.weight".to_string(), NpyArray::new(vec![2, 2], vec![0.5, -1.0, 2.0, 0.0]).unwrap()),
+            ("layers.0.bias".to_string(), NpyArray::new(vec![2], vec![0.25, -0.75]).unwrap()),
+            ("empty".to_string(), NpyArray::new(vec![0, 3], vec![]).unwrap()),
+        ];
+        assert_eq!(read_npz::<f64>(&write_npz(&arrays).unwrap()).unwrap(), arrays);
+    }
+
+    #[test]
+    fn numpy_files_load() {
+        let array: NpyArray<f64> = read_npy(NUMPY_NPY).unwrap();
+        assert_eq!(array, NpyArray::new(vec![2, 3], vec![1.5, -2.0, 0.25, 3.0, 0.0, -1.0]).unwrap());
+        // Our own writer lays the file out the same way
+        assert_eq!(write_npy(&array).unwrap(), NUMPY_NPY);
+
+        let arrays: Vec<(String, NpyArray<f64>)> = read_npz(NUMPY_NPZ).unwrap();
+        let weights = [0.1f32, 0.2, 0.3, 0.4].map(|w| w as f64).to_vec();
+        assert_eq!(arrays, vec![
+            ("weight".to_string(), NpyArray::new(vec![2, 2], weights).unwrap()),
+            ("bias".to_string(), NpyArray::new(vec![1], vec![-0.5]).unwrap()),
+        ]);
+    }
+
+    #[test]
+    fn oversized_shapes_are_errors() {
+        assert!(matches!(NpyArray::new(vec![usize::MAX, 2], vec![1.0]), Err(NnError::InvalidFormat(_))));
+        let header = format!("{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, 2), }}\n", usize::MAX);
+        let mut bytes = NPY_MAGIC.to_vec();
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        let error = read_npy::<f64>(&bytes).err();
+        assert!(matches!(error, Some(NnError::InvalidFormat(message)) if message.contains("too many")));
+    }
+
+    #[test]
+    fn npz_archives_with_too_many_entries_are_errors() {
+        let array = NpyArray::new(vec![0], Vec::<f64>::new()).unwrap();
+        let arrays: Vec<(String, NpyArray<f64>)> = (0..=u16::MAX as usize)
+            .map(|i| (i.to_string(), array.clone()))
+            .collect();
+        assert!(matches!(write_npz(&arrays), Err(NnError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn f64_values_out_of_range_for_f32_are_errors() {
+        let bytes = write_npy(&NpyArray::new(vec![1], vec![1e300]).unwrap()).unwrap();
+        assert!(matches!(read_npy::<f32>(&bytes), Err(NnError::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn weight_arrays_need_all_or_no_biases_per_layer() {
+        let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(3).layers(&[2, 1]).seed(1).build().unwrap();
+        let names = |mlp: &MultiLayerPerceptron<f64>| -> Vec<String> {
+            mlp.weight_arrays().unwrap().into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(names(&mlp), ["layers.0.weight", "layers.0.bias", "layers.1.weight", "layers.1.bias"]);
+        let arrays = mlp.weight_arrays().unwrap();
+        assert_eq!(arrays[0].1.shape, [2, 3]);
+        assert_eq!(arrays[1].1.data, [0, 1].map(|j| mlp.layers[0].neurons[j].bias.as_ref().unwrap().get_data()));
+
+        let unbiased: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(3).layers(&[2, 1]).bias(false).build()
+            .unwrap();
+        assert_eq!(names(&unbiased), ["layers.0.weight", "layers.1.weight"]);
+
+        let mut mixed = mlp;
+        mixed.layers[0].neurons[1].bias = None;
+        assert_eq!(mixed.weight_arrays().err(),
+                   Some(NnError::InvalidConfig("Only 1 of layer 0's 2 neurons have a bias".to_string())));
+        assert!(matches!(mixed.to_safetensors(), Err(NnError::InvalidConfig(_))));
+    }
+}
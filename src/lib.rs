@@ -1,10 +1,39 @@
+pub mod arena;
+pub mod checkpoint;
+pub mod io;
 pub mod nn;
+pub mod optim;
+pub mod parallel;
+pub mod parser;
+pub mod safetensors;
 pub mod scalar;
+pub mod serialize;
+pub mod symbolic;
+pub mod tape;
+pub mod train;
 
+pub use arena::{Graph, Var};
+pub use checkpoint::{Checkpoint, OptimizerCheckpoint, CHECKPOINT_VERSION};
+pub use io::NpyArray;
+pub use nn::{Activation, ActivationLayer, Dropout, Initializer, LayerNorm, MlpBuilder, Sequential, Softmax};
+pub use nn::Architecture;
 pub use nn::Layer;
+pub use nn::{LayerSummary, ModelSummary};
+pub use nn::Module;
+pub use nn::NnError;
+pub use nn::StateDictKeys;
 pub use nn::Neuron;
 pub use nn::MultiLayerPerceptron;
+pub use optim::{Adam, AdamState};
+pub use parallel::SharedParameters;
+pub use parser::{parse, ParseError};
+pub use scalar::CustomOp;
 pub use scalar::Scalar;
+pub use scalar::{is_anomaly_detection_enabled, set_detect_anomaly};
+pub use serialize::JSON_FORMAT_VERSION;
+pub use symbolic::Expr;
+pub use tape::Tape;
+pub use train::DataParallelTrainer;
 
 pub fn arrange(start: f64, stop: f64, step: f64) -> impl Iterator<Item = f64> {
     let count = ((stop - start) / step).ceil() as usize;
@@ -1,12 +1,47 @@
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::io::Write;
 
 use clap::Parser;
 use num_traits::Float;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
+use micro_grad::{Adam, Checkpoint};
+use micro_grad::CustomOp;
+use micro_grad::DataParallelTrainer;
+use micro_grad::Expr;
+use micro_grad::Initializer;
+use micro_grad::io;
+use micro_grad::NpyArray;
+use micro_grad::ParseError;
+use micro_grad::Var;
 use micro_grad::Layer;
+use micro_grad::{Activation, ActivationLayer, Dropout, LayerNorm, Sequential, Softmax};
+use micro_grad::Module;
 use micro_grad::Neuron;
 use micro_grad::MultiLayerPerceptron;
+use micro_grad::NnError;
 use micro_grad::Scalar;
+use micro_grad::SharedParameters;
+use micro_grad::Tape;
+
+// sqrt(x^2 + y^2), defined outside the crate
+struct Hypot;
+
+impl CustomOp<f64> for Hypot {
+    fn name(&self) -> &str {
+        "hypot"
+    }
+
+    fn forward(&self, inputs: &[f64]) -> f64 {
+        inputs[0].hypot(inputs[1])
+    }
+
+    fn backward(&self, inputs: &[f64], output: f64, out_grad: f64) -> Vec<f64> {
+        inputs.iter().map(|x| x / output * out_grad).collect()
+    }
+}
 
 fn tensor_test() {
     println!("\n------ MLP ------");
@@ -67,6 +102,42 @@ fn tensor_test() {
     sub.backward();
     println!("{}", sub); // sub { data: -2.0, grad: 0 }
 
+    println!("\n------ Custom op ------");
+    let x = Scalar::new(3.0); x.set_label("x");
+    let y = Scalar::new(4.0); y.set_label("y");
+    let hypot = Scalar::custom(Hypot, &[x.clone(), y.clone()]).expect("Hypot returns a gradient per input");
+    hypot.backward();
+    println!("{}", hypot); // hypot(x, y) { data: 5.0, grad: 1.0 }
+    println!("{}", x); // x { data: 3.0, grad: 0.6 }
+    println!("{}", y); // y { data: 4.0, grad: 0.8 }
+
+    println!("\n------ Gradient hooks ------");
+    let x = Scalar::new(0.5); x.set_label("x");
+    let square = x.pow(2.0); square.set_label("square");
+    let scaled = square.tanh().mul_number(10.0); scaled.set_label("scaled");
+    square.register_hook(|grad| { println!("square's gradient: {:.4}", grad); grad });
+    x.register_hook(|grad: f64| -grad.clamp(-1.0, 1.0)); // Clip, then reverse
+    scaled.backward(); // square's gradient: 9.4001
+    println!("{}", x); // x { data: 0.5, grad: -1.0 }
+    x.clear_hooks();
+
+    println!("\n------ Seeded backward ------");
+    let x = Scalar::new(3.0); x.set_label("x");
+    let square = x.pow(2.0); square.set_label("square");
+    square.backward_with(0.5);
+    println!("{}", square); // square { data: 9.0, grad: 0.5 }
+    println!("{}", x); // x { data: 3, grad: 3 }
+
+    println!("\n------ Retained backward ------");
+    let x = Scalar::new(3.0); x.set_label("x");
+    let double = x.mul_number(2.0); double.set_label("double");
+    let square = x.pow(2.0); square.set_label("square");
+    double.backward_retain(1.0);
+    square.backward_retain(0.5);
+    println!("{}", double); // double { data: 6.0, grad: 1.0 }
+    println!("{}", square); // square { data: 9.0, grad: 0.5 }
+    println!("{}", x); // x { data: 3, grad: 5 }
+
     println!("\n------ Manual tanh ------");
     let x1 = Scalar::new(2.0); x1.set_label("x1");
     let x2 = Scalar::new(0.0); x2.set_label("x2");
@@ -89,7 +160,7 @@ fn tensor_test() {
     println!("{}", w1);         // w1 { data: -3.0000, grad: 1.0000 }
 }
 
-fn mse<T: Float + Copy + Display + std::ops::AddAssign + 'static>(ground_truths: &Vec<Scalar<T>>, predictions: &Vec<Scalar<T>>) -> Scalar<T> {
+fn mse<T: Float + Copy + Display + std::ops::AddAssign + 'static>(ground_truths: &[Scalar<T>], predictions: &[Scalar<T>]) -> Scalar<T> {
     let mut loss = Scalar::new(T::zero());
     for (gt, pred) in ground_truths.iter().zip(predictions.iter()) {
         let diff_squared = (pred - gt).pow(T::from(2.0).unwrap());
@@ -103,7 +174,7 @@ fn mse<T: Float + Copy + Display + std::ops::AddAssign + 'static>(ground_truths:
 fn nn_test() {
 
     println!("\n------ Neuron ------");
-    let neuron = Neuron::new(1);
+    let neuron = Neuron::new(1).expect("Invalid neuron");
     let inputs = vec![0.5];
     let prediction = neuron.forward_with_numbers(&inputs).expect(""); prediction.set_label("neuron_prediction");
     let gt =  Scalar::new_with_label(0.5, "neuron_gt");
@@ -114,9 +185,9 @@ fn nn_test() {
     println!("{}", neuron.weights[0]);
 
     println!("\n------ Layer ------");
-    let layer = Layer::<f64>::new(2,1);
+    let layer = Layer::<f64>::new(2,1).expect("Invalid layer");
     let inputs = vec![0.5,-0.5];
-    let prediction = &layer.forward_with_numbers(&inputs)[0]; prediction.set_label("layer_prediction");
+    let prediction = &layer.forward_with_numbers(&inputs).expect("Wrong number of inputs")[0]; prediction.set_label("layer_prediction");
     let gt =  Scalar::new_with_label(0.5, "layer_gt");
     let loss = (prediction - &gt).pow(2.0);
     loss.set_label("layer_loss");
@@ -125,9 +196,9 @@ fn nn_test() {
     println!("{}", layer.neurons[0].weights[0]);
 
     println!("\n------ Simple Multi-Layer Perceptron ------");
-    let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, vec![4, 1]);
+    let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, &[4, 1]).expect("Invalid model");
     let inputs = vec![2.0, 3.0, -1.0];
-    let prediction = &mlp.forward(&inputs)[0]; prediction.set_label("mlp_prediction");
+    let prediction = &mlp.forward(&inputs).expect("Wrong number of inputs")[0]; prediction.set_label("mlp_prediction");
     let gt = Scalar::new_with_label(-1.0, "mlp_gt");
     let loss = (prediction - &gt).pow(2.0);
     loss.set_label("mlp_loss");
@@ -135,29 +206,48 @@ fn nn_test() {
     println!("{}", loss);
     println!("{}", mlp.layers[0].neurons[1].weights[0]);
 
+    println!("\n------ Chained models and input gradients ------");
+    let encoder: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, &[2]).expect("Invalid model");
+    let head: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(2, &[1]).expect("Invalid model");
+    let x: Vec<Scalar<f64>> = ["x0", "x1", "x2"].iter().zip(inputs.iter())
+        .map(|(label, v)| Scalar::new_with_label(*v, label))
+        .collect();
+    let encoded = encoder.forward_scalars(&x).expect("Wrong number of inputs");
+    let output = &head.forward_scalars(&encoded).expect("Wrong number of inputs")[0];
+    output.backward();
+    // Saliency: how much each input moves the output
+    for input in &x {
+        println!("d output / d {} = {:.4}", input.get_label(), input.get_grad());
+    }
+    // Adversarial example: nudge each input against its gradient to push the output down
+    let epsilon = 0.1;
+    let adversarial: Vec<f64> = x.iter().map(|input| input.get_data() - epsilon * input.get_grad().signum()).collect();
+    let pushed = head.forward_scalars(&encoder.forward(&adversarial).expect("Wrong number of inputs"))
+        .expect("Wrong number of inputs");
+    println!("Output {:.4} -> {:.4}", output.get_data(), pushed[0].get_data());
+
     println!("\n------ Complex Multi-Layer Perceptron ------");
-    let mlp = MultiLayerPerceptron::new(3, vec![4,4,1]);
+    let mlp = MultiLayerPerceptron::new(3, &[4,4,1]).expect("Invalid model");
     let xs = [
         vec![2.0, 3.0, -1.0],
         vec![3.0, -1.0, 0.5],
         vec![0.5, 1.0, 1.0],
         vec![1.0, 1.0, -1.0],
     ];
-    let ys = [1.0, -1.0, -1.0, 1.0].map(|y| Scalar::new(y)); // Desired output for row of xs
-    // flat_map with, I think, concatenate all the outputs together. I know it's one each, and I want to combine them
-    // all into one array, so this is fine, but I'd need to do something more sophisticated for the general case
-    let y_predictions: Vec<Scalar<f64>> = xs.iter().flat_map(|x| mlp.forward(x)).collect();
+    let ys = [1.0, -1.0, -1.0, 1.0].map(Scalar::new); // Desired output for row of xs
+    // One output per sample, so concatenating the samples' outputs lines them up with ys
+    let y_predictions: Vec<Scalar<f64>> = mlp.forward_batch(&xs).expect("Wrong number of inputs").concat();
     // for prediction in &y_predictions {
     //     println!("{}", prediction);
     // }
 
-    let loss = mse(&ys.to_vec(), &y_predictions);
+    let loss = mse(&ys, &y_predictions);
     loss.set_label("loss");
     loss.backward();
     println!("{}", loss);
 
-    // println!("\n------ {} Parameters ------", mlp.parameters().len());
-    // mlp.parameters().iter().for_each(|p| println!("{}", p));
+    println!("\n------ Summary ------");
+    println!("{}", mlp.summary());
 
     println!("\n------ Optimize ------");
     println!("{}", mlp.layers[0].neurons[0].weights[0]);
@@ -169,8 +259,8 @@ fn nn_test() {
 
     println!("\n------ Additional Forward Passes ------");
     for _ in 0..5 {
-        let y_predictions: Vec<Scalar<f64>> = xs.iter().flat_map(|x| mlp.forward(x)).collect();
-        let loss = mse(&ys.to_vec(), &y_predictions);
+        let y_predictions: Vec<Scalar<f64>> = mlp.forward_batch(&xs).expect("Wrong number of inputs").concat();
+        let loss = mse(&ys, &y_predictions);
         loss.set_label("loss");
         mlp.zero_grad();
         loss.backward();
@@ -181,32 +271,585 @@ fn nn_test() {
     }
 }
 
+// Works with any model
+fn sgd_step<M: Module<f64>>(model: &M, learning_rate: f64) {
+    for p in model.parameters() {
+        p.add_to_data(p.get_grad() * -learning_rate);
+    }
+}
+
+// A user-defined module: scales each input by its own factor
+struct Scale {
+    factors: Vec<Scalar<f64>>,
+    training: bool,
+}
+
+impl Module<f64> for Scale {
+    fn forward(&self, inputs: &[Scalar<f64>]) -> Result<Vec<Scalar<f64>>, NnError> {
+        if inputs.len() != self.factors.len() {
+            Err(NnError::ShapeMismatch { expected: self.factors.len(), actual: inputs.len() })?
+        }
+        Ok(inputs.iter().zip(self.factors.iter()).map(|(x, factor)| x * factor).collect())
+    }
+
+    fn parameters(&self) -> Vec<Scalar<f64>> {
+        self.factors.clone()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Scalar<f64>)> {
+        self.factors.iter().enumerate().map(|(i, f)| (format!("factors.{}", i), f.clone())).collect()
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+fn module_test() {
+    println!("\n------ Named parameters ------");
+    let mut mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(2, &[2, 1]).expect("Invalid model");
+    for (name, p) in mlp.named_parameters() {
+        println!("{}: {:.4}", name, p.get_data());
+    }
+    mlp.eval();
+    println!("Training: {}", mlp.is_training()); // Training: false
+
+    println!("\n------ State dict ------");
+    println!("{}", mlp.layers[1].neurons[0].weights[1]); // layers.1.neurons.0.w1 { data: ..., grad: 0.0000 }
+    let state = mlp.state_dict();
+    let copy: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(2, &[2, 1]).expect("Invalid model");
+    copy.load_state_dict(&state, true).expect("State dict doesn't match");
+    println!("Copied: {}", copy.state_dict() == state); // Copied: true
+
+    // Transfer learning: reuse the first layer of a model with a different output layer
+    let wider: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(2, &[2, 3]).expect("Invalid model");
+    let first_layer: HashMap<String, f64> = state.into_iter().filter(|(name, _)| name.starts_with("layers.0.")).collect();
+    if let Err(error) = wider.load_state_dict(&first_layer, true) {
+        println!("{}", error); // State dict doesn't match the model. Missing keys: ["layers.1.neurons.0.w0", ...
+    }
+    let keys = wider.load_state_dict(&first_layer, false).expect("Non-strict loading doesn't fail");
+    println!("Loaded {} parameters, {} missing, {} unexpected",
+             first_layer.len(), keys.missing.len(), keys.unexpected.len()); // Loaded 6 parameters, 9 missing, 0 unexpected
+
+    println!("\n------ Builder ------");
+    let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(3)
+        .layers(&[8, 8])
+        .layer(1)
+        .activation(Activation::Relu)
+        .output_activation(Activation::Identity)
+        .initializer(Initializer::He)
+        .bias(false)
+        .seed(42)
+        .build()
+        .expect("Invalid model");
+    println!("{:?}", mlp.architecture().layer_activations); // [Relu, Relu, Identity]
+    println!("{} parameters", mlp.parameters().len()); // 96 parameters
+    let zero_width = MultiLayerPerceptron::<f64>::builder(3).layers(&[4, 0, 1]).build();
+    if let Err(error) = zero_width {
+        println!("{}", error); // Invalid configuration: Layer 1 has zero width
+    }
+
+    println!("\n------ User-defined module ------");
+    let scale = Scale { factors: vec![Scalar::new_with_label(1.0, "f0"), Scalar::new_with_label(2.0, "f1")], training: true };
+    let inputs = [Scalar::new(3.0), Scalar::new(4.0)];
+    let loss = (&scale.forward(&inputs).expect("Wrong number of inputs")[1] - &Scalar::new(4.0)).pow(2.0);
+    loss.backward();
+    for (name, p) in scale.named_parameters() {
+        println!("{}: {}", name, p); // factors.1: f1 { data: 2.0000, grad: 32.0000 }
+    }
+    sgd_step(&scale, 0.01);
+    println!("{}", scale.factors[1]); // f1 { data: 1.6800, grad: 32.0000 }
+}
+
+fn sequential_test() {
+    println!("\n------ Sequential ------");
+    let mut model: Sequential<f64> = Sequential::new(vec![
+        Box::new(Layer::new_with_activation(3, 8, Activation::Identity).expect("Invalid layer")),
+        Box::new(ActivationLayer::new(Activation::Relu)),
+        Box::new(Dropout::new(0.25).expect("Invalid dropout")),
+        Box::new(LayerNorm::new(8).expect("Invalid layer norm")),
+        Box::new(Layer::new_with_activation(8, 2, Activation::Identity).expect("Invalid layer")),
+        Box::new(Softmax::new()),
+    ]).expect("Stages don't fit together");
+    println!("{}", model.summary());
+    // The same data, for tooling
+    let summary = model.summary();
+    let widest = summary.layers.iter().max_by_key(|l| l.num_parameters).expect("No layers");
+    println!("{} of {} parameters are in layer {}", widest.num_parameters, summary.num_parameters, widest.name); // 32 of 66 parameters are in layer 0
+
+    let inputs: Vec<Scalar<f64>> = [2.0, 3.0, -1.0].iter().map(|x| Scalar::new(*x)).collect();
+    model.eval();
+    let probabilities = model.forward(&inputs).expect("Wrong number of inputs");
+    let total: f64 = probabilities.iter().map(|p| p.get_data()).sum();
+    println!("Probabilities: {:.4?}, total: {:.4}", probabilities.iter().map(|p| p.get_data()).collect::<Vec<f64>>(), total);
+
+    println!("\n------ Shape checking ------");
+    let mismatched: Result<Sequential<f64>, NnError> = Sequential::new(vec![
+        Box::new(Layer::new(3, 8).expect("Invalid layer")),
+        Box::new(ActivationLayer::new(Activation::Sigmoid)),
+        Box::new(Layer::new(4, 1).expect("Invalid layer")),
+    ]);
+    if let Err(error) = mismatched {
+        println!("{}", error); // Stage 2 expects 4 inputs, but stage 1 produces 8
+    }
+
+    // Wrong input lengths and bad configurations come back as errors that can be handled
+    match model.forward(&inputs[..2]) {
+        Err(NnError::ShapeMismatch { expected, actual }) => println!("Got {} inputs, expected {}", actual, expected),
+        other => println!("Unexpected result: {:?}", other.map(|outputs| outputs.len())),
+    }
+    if let Err(error) = Dropout::new(1.5) {
+        println!("{}", error); // Invalid configuration: Dropout probability must be in [0, 1), but is 1.5
+    }
+    if let Err(error) = MultiLayerPerceptron::<f64>::new(3, &[]) {
+        println!("{}", error); // The model has no layers
+    }
+}
+
 fn train() {
     let learning_rate = 0.1;
-    let mlp = MultiLayerPerceptron::new(3, vec![4,4,1]);
+    let mlp = MultiLayerPerceptron::new(3, &[4,4,1]).expect("Invalid model");
     let xs = [
         vec![2.0, 3.0, -1.0],
         vec![3.0, -1.0, 0.5],
         vec![0.5, 1.0, 1.0],
         vec![1.0, 1.0, -1.0],
     ];
-    let ys = [1.0, -1.0, -1.0, 1.0].map(|y| Scalar::new(y)); 
+    let ys = [1.0, -1.0, -1.0, 1.0].map(Scalar::new); 
 
     for k in 0..10 {
         // Forward pass
-        let y_predictions: Vec<Scalar<f64>> = xs.iter().flat_map(|x| mlp.forward(x)).collect();
-        let loss = mse(&ys.to_vec(), &y_predictions);
+        let y_predictions: Vec<Scalar<f64>> = mlp.forward_batch(&xs).expect("Wrong number of inputs").concat();
+        let loss = mse(&ys, &y_predictions);
 
         // Backward pass
         mlp.zero_grad();
         loss.backward();
 
         // Update
-        for p in mlp.parameters() {
-            p.add_to_data(p.get_grad() * -learning_rate);
-        }
+        sgd_step(&mlp, learning_rate);
         println!("Step {} loss: {:0.4}", k, loss.get_data());
     }
+
+    // Evaluate the whole dataset in one call, without building a graph
+    let predictions = mlp.predict_batch(&xs).expect("Wrong number of inputs");
+    println!("Predictions: {:.4?}", predictions);
+}
+
+fn optimize_test() {
+    println!("\n------ Optimize a traced graph ------");
+    let x1 = Scalar::new(2.0); x1.set_label("x1");
+    let x2 = Scalar::new(0.0); x2.set_label("x2");
+    let w1 = Scalar::new(-3.0); w1.set_label("w1");
+    let w2 = Scalar::new(1.0); w2.set_label("w2");
+    let b = Scalar::new(6.8814); b.set_label("b");
+    let sum = &(&(&x1 * &w1) + &(&x2 * &w2)) + &b;
+    // Only constants: folds to a single constant
+    let scale = Scalar::new(1.0).add_number(1.0);
+    // The numerator and denominator both compute sum * 2 and its exp
+    let numerator = (&sum * &scale).exp().add_number(-1.0);
+    let denominator = sum.mul_number(2.0).exp().add_number(1.0);
+    let manual_tanh = &numerator / &denominator;
+
+    let inputs = [("x1", &x1), ("x2", &x2)];
+    let parameters = [w1, w2, b];
+    let mut original = Tape::trace(&manual_tanh, &inputs, &parameters).expect("Failed to trace");
+    let mut optimized = Tape::trace(&manual_tanh, &inputs, &parameters).expect("Failed to trace");
+    optimized.optimize();
+    println!("Instructions: {} -> {}", original.len(), optimized.len()); // Instructions: 23 -> 18
+
+    for tape in [&mut original, &mut optimized] {
+        tape.set_input("x2", 0.5).expect("Unknown input");
+        let output = tape.forward();
+        tape.backward();
+        let grads: Vec<f64> = tape.parameter_grads().map(|(_, grad)| grad).collect();
+        println!("output: {:.4}, x1 grad: {:.4}, x2 grad: {:.4}, parameter grads: {:.4?}",
+            output, tape.grad("x1").unwrap(), tape.grad("x2").unwrap(), grads);
+    }
+}
+
+fn symbolic_test() {
+    println!("\n------ Symbolic differentiation ------");
+    let x = Scalar::new(0.5); x.set_label("x");
+    let y = Scalar::new(-1.5); y.set_label("y");
+    let f = (&(&x * &y) + &x.pow(3.0)).tanh().add_number(1.0);
+    f.backward();
+    println!("f = {}", Expr::from_scalar(&f).expect("No symbolic form"));
+
+    let variables = HashMap::from([("x".to_string(), x.get_data()), ("y".to_string(), y.get_data())]);
+    for leaf in [&x, &y] {
+        let gradient = Expr::gradient(&f, leaf).expect("No symbolic gradient");
+        let value = gradient.evaluate(&variables).expect("Missing variable");
+        println!("df/d{} = {}", leaf.get_label(), gradient);
+        println!("  symbolic: {:.4}, backward: {:.4}", value, leaf.get_grad());
+    }
+
+    println!("\n------ Simplification ------");
+    let z = Scalar::new(2.0); z.set_label("z");
+    let expression = &(&z.mul_number(1.0) * &z.pow(2.0)) / &z.add_number(0.0);
+    println!("{}", Expr::from_scalar(&expression).expect("No symbolic form")); // z * 1 * z^2 / (z + 0)
+    println!("{}", Expr::from_scalar(&expression).expect("No symbolic form").simplify()); // z^3 / z
+}
+
+fn parse_test() {
+    println!("\n------ Parse an expression ------");
+    let variables: HashMap<String, Scalar<f64>> = [("x1", 2.0), ("x2", 0.0), ("w1", -3.0), ("w2", 1.0), ("b", 6.8814)]
+        .iter()
+        .map(|(name, value)| (name.to_string(), Scalar::new(*value)))
+        .collect();
+    let tanh = micro_grad::parse("tanh(x1*w1 + x2*w2 + b)", &variables).expect("Failed to parse");
+    tanh.backward();
+    println!("{}", tanh); // tanh((((x1 * w1) + (x2 * w2)) + b)) { data: 0.7071, grad: 1.0000 }
+    println!("{}", variables["w1"]); // w1 { data: -3.0000, grad: 1.0000 }
+    println!("{}", variables["x1"]); // x1 { data: 2.0000, grad: -1.4999 }
+
+    println!("\n------ Parse errors ------");
+    for source in ["x1 * (w1 + ", "x1 $ w1", "sin(x1)", "x1 * y", "x1 ^ w1"] {
+        if let Err(error) = micro_grad::parse(source, &variables) {
+            println!("{:<14} {}", source, error);
+        }
+    }
+}
+
+const REPL_HELP: &str = "\
+  name = <expression>  Define a variable, e.g. x = 2.0
+  <expression>         Evaluate, e.g. tanh(x * w + b), and show the gradient of every variable
+  :graph               Print the graph of the last expression
+  :vars                List the variables
+  :reset               Forget every variable and the last expression
+  :help                Show this help
+  :quit                Exit";
+
+// The REPL's variables and last expression, kept apart from stdin and stdout so evaluation can be tested
+#[derive(Default)]
+struct ReplSession {
+    variables: HashMap<String, Scalar<f64>>,
+    last: Option<Scalar<f64>>,
+}
+
+impl ReplSession {
+    /// Run a command, assignment or expression, returning what to print. Error positions count characters from the
+    /// start of `line`.
+    fn evaluate(&mut self, line: &str) -> Result<String, ParseError> {
+        match line.trim() {
+            "" => Ok(String::new()),
+            ":help" => Ok(REPL_HELP.to_string()),
+            ":reset" => {
+                self.variables.clear();
+                self.last = None;
+                Ok(String::new())
+            }
+            ":vars" => Ok(self.sorted_names().iter().map(|name| self.variables[*name].to_string())
+                .collect::<Vec<String>>().join("\n")),
+            ":graph" => Ok(self.last.as_ref().map_or("No expression yet".to_string(), |s| s.graph_string())),
+            command if command.starts_with(':') => Ok(format!("Unknown command {}. Type :help for help.", command)),
+            _ => match line.split_once('=') {
+                Some((name, source)) => self.assign(name, source, line),
+                None => self.evaluate_expression(line),
+            },
+        }
+    }
+
+    fn assign(&mut self, name: &str, source: &str, line: &str) -> Result<String, ParseError> {
+        let trimmed = name.trim();
+        if trimmed.is_empty() || !trimmed.chars().all(|c| c.is_alphanumeric() || c == '_') ||
+            trimmed.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(ParseError {
+                position: name.chars().count() - name.trim_start().chars().count(),
+                message: format!("Invalid variable name {}", trimmed),
+            });
+        }
+        // Report positions relative to the whole line. Positions count characters, not bytes.
+        let offset = line[..line.len() - source.len()].chars().count();
+        let value = micro_grad::parse(source, &self.variables)
+            .map_err(|error| ParseError { position: error.position + offset, ..error })?;
+        let variable = Scalar::new_with_label(value.get_data(), trimmed);
+        let output = variable.to_string();
+        self.variables.insert(trimmed.to_string(), variable);
+        Ok(output)
+    }
+
+    fn evaluate_expression(&mut self, line: &str) -> Result<String, ParseError> {
+        let scalar = micro_grad::parse(line, &self.variables)?;
+        self.variables.values().for_each(|v| v.zero_grad());
+        scalar.backward();
+        let mut output = format!("= {:.4}", scalar.get_data());
+        for name in self.sorted_names() {
+            output += &format!("\n  d/d{} = {:.4}", name, self.variables[name].get_grad());
+        }
+        self.last = Some(scalar);
+        Ok(output)
+    }
+
+    fn sorted_names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.variables.keys().collect();
+        names.sort();
+        names
+    }
+}
+
+fn repl() {
+    println!("micro-grad REPL. Type :help for help.");
+    let mut session = ReplSession::default();
+    let mut lines = std::io::stdin().lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush().expect("Failed to flush stdout");
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        match line.trim() {
+            ":quit" | ":q" => break,
+            _ => match session.evaluate(&line) {
+                Ok(output) if output.is_empty() => (),
+                Ok(output) => println!("{}", output),
+                Err(error) => println!("Error {}", error),
+            },
+        }
+    }
+}
+
+fn anomaly_test() {
+    println!("\n------ Anomaly detection ------");
+    micro_grad::set_detect_anomaly(true);
+    let x = Scalar::new(1.0); x.set_label("x");
+    let y = Scalar::new(0.0); y.set_label("y");
+
+    // Detection panics, so catch the panic to show the report instead of printing it as a crash
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(|_| {}));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| &x / &y));
+    std::panic::set_hook(default_hook);
+    micro_grad::set_detect_anomaly(false);
+
+    match result {
+        Ok(div) => println!("No anomaly: {}", div),
+        // Anomaly detected: forward produced inf at '(y^-1)' (op: ^-1), producers: [y { data: 0.0000, grad: 0.0000 }]
+        Err(payload) => println!("{}", payload.downcast_ref::<String>().map_or("Unknown panic", |m| m.as_str())),
+    }
+}
+
+fn parallel_test() {
+    let mlp = MultiLayerPerceptron::new(3, &[4, 4, 1]).expect("Invalid model");
+    let architecture = mlp.architecture();
+    let shared = SharedParameters::from_scalars(&mlp.parameters());
+    let samples = [
+        (vec![2.0, 3.0, -1.0], 1.0),
+        (vec![3.0, -1.0, 0.5], -1.0),
+        (vec![0.5, 1.0, 1.0], -1.0),
+        (vec![1.0, 1.0, -1.0], 1.0),
+    ];
+
+    println!("\n------ Parallel forward ------");
+    let predictions = shared.map_batch(&samples, 2, |graph, parameters, (x, _)| {
+        let inputs: Vec<Var<f64>> = x.iter().map(|v| graph.var(*v)).collect();
+        architecture.forward_var(parameters, &inputs).expect("Wrong number of inputs")[0].get_data()
+    });
+    let expected: Vec<f64> = samples.iter().map(|(x, _)| mlp.predict(x).expect("Wrong number of inputs")[0]).collect();
+    println!("Threads: {:.4?}", predictions);
+    println!("Scalar:  {:.4?}", expected);
+
+    println!("\n------ Parallel training ------");
+    let learning_rate = 0.1;
+    for k in 0..10 {
+        shared.zero_grad();
+        let loss = shared.backward_batch(&samples, 2, |graph, parameters, (x, y)| {
+            let inputs: Vec<Var<f64>> = x.iter().map(|v| graph.var(*v)).collect();
+            let prediction = architecture.forward_var(parameters, &inputs).expect("Wrong number of inputs")[0];
+            (&prediction - &graph.var(*y)).pow(2.0)
+        });
+        shared.step(learning_rate);
+        println!("Step {} loss: {:0.4}", k, loss);
+    }
+    shared.copy_to_scalars(&mlp.parameters());
+}
+
+fn train_parallel() {
+    let xs = vec![
+        vec![2.0, 3.0, -1.0],
+        vec![3.0, -1.0, 0.5],
+        vec![0.5, 1.0, 1.0],
+        vec![1.0, 1.0, -1.0],
+    ];
+    let ys = vec![vec![1.0], vec![-1.0], vec![-1.0], vec![1.0]];
+    let seed = 42;
+
+    let mut final_parameters = Vec::new();
+    for run in 0..2 {
+        println!("\n------ Data-parallel training, run {} ------", run);
+        let mlp: MultiLayerPerceptron<f64> =
+            MultiLayerPerceptron::new_with_rng(3, &[4, 4, 1], &mut StdRng::seed_from_u64(seed)).expect("Invalid model");
+        let mut trainer = DataParallelTrainer::new(0.1, 2, 2, seed);
+        for epoch in 0..10 {
+            let loss = trainer.train_epoch(&mlp, &xs, &ys).expect("Samples don't fit the model");
+            println!("Epoch {} loss: {:0.4}", epoch, loss);
+        }
+        final_parameters.push(mlp.parameters().iter().map(|p| p.get_data()).collect::<Vec<f64>>());
+    }
+    println!("\nRuns identical: {}", final_parameters[0] == final_parameters[1]);
+}
+
+fn save_test() {
+    println!("\n------ Save and load as JSON ------");
+    let xs = vec![
+        vec![2.0, 3.0, -1.0],
+        vec![3.0, -1.0, 0.5],
+        vec![0.5, 1.0, 1.0],
+        vec![1.0, 1.0, -1.0],
+    ];
+    let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(3)
+        .layers(&[4, 4, 2])
+        .output_activation(Activation::Identity)
+        .build()
+        .expect("Invalid model");
+    let path = std::env::temp_dir().join("micro-grad-mlp.json");
+    mlp.save_json(&path).expect("Failed to save");
+    let loaded: MultiLayerPerceptron<f64> = MultiLayerPerceptron::load_json(&path).expect("Failed to load");
+    println!("Loaded {:?}", loaded.architecture().layer_num_outputs); // Loaded [4, 4, 2]
+
+    let bits = |model: &MultiLayerPerceptron<f64>| -> Vec<u64> {
+        model.predict_batch(&xs).expect("Wrong number of inputs").concat().iter().map(|y| y.to_bits()).collect()
+    };
+    println!("Predictions bit-identical: {}", bits(&mlp) == bits(&loaded)); // Predictions bit-identical: true
+
+    let mlp32: MultiLayerPerceptron<f32> = MultiLayerPerceptron::new(3, &[4, 1]).expect("Invalid model");
+    let loaded32: MultiLayerPerceptron<f32> =
+        MultiLayerPerceptron::from_json(&mlp32.to_json().expect("Failed to save")).expect("Failed to load");
+    let identical = mlp32.state_dict().iter().all(|(name, w)| loaded32.state_dict()[name].to_bits() == w.to_bits());
+    println!("f32 weights bit-identical: {}", identical); // f32 weights bit-identical: true
+
+    let future = mlp.to_json().expect("Failed to save")
+        .replace(&format!("\"version\": {}", micro_grad::JSON_FORMAT_VERSION), "\"version\": 99");
+    if let Err(error) = MultiLayerPerceptron::<f64>::from_json(&future) {
+        println!("{}", error); // Invalid format: Unsupported version 99, expected 1
+    }
+    std::fs::remove_file(&path).expect("Failed to clean up");
+}
+
+fn checkpoint_test() {
+    let xs = vec![
+        vec![2.0, 3.0, -1.0],
+        vec![3.0, -1.0, 0.5],
+        vec![0.5, 1.0, 1.0],
+        vec![1.0, 1.0, -1.0],
+    ];
+    let ys = [1.0, -1.0, -1.0, 1.0].map(Scalar::new);
+    let new_model = |seed| -> MultiLayerPerceptron<f64> {
+        MultiLayerPerceptron::builder(3).layers(&[4, 4, 1]).seed(seed).build().expect("Invalid model")
+    };
+    let train_step = |mlp: &MultiLayerPerceptron<f64>, optimizer: &mut Adam<f64>| -> f64 {
+        let loss = mse(&ys, &mlp.forward_batch(&xs).expect("Wrong number of inputs").concat());
+        optimizer.zero_grad();
+        loss.backward();
+        optimizer.step();
+        loss.get_data()
+    };
+
+    println!("\n------ Training without stopping ------");
+    let mlp = new_model(42);
+    let mut optimizer = Adam::new(mlp.parameters(), 0.05);
+    for _ in 0..10 {
+        train_step(&mlp, &mut optimizer);
+    }
+    let uninterrupted: Vec<u64> = mlp.parameters().iter().map(|p| p.get_data().to_bits()).collect();
+    println!("Final loss: {:.6}", train_step(&mlp, &mut optimizer));
+
+    println!("\n------ Stopping and resuming from a checkpoint ------");
+    let path = std::env::temp_dir().join("micro-grad.ckpt");
+    {
+        let mlp = new_model(42);
+        let mut optimizer = Adam::new(mlp.parameters(), 0.05);
+        for _ in 0..5 {
+            train_step(&mlp, &mut optimizer);
+        }
+        Checkpoint::new(&mlp, Some(&optimizer), optimizer.num_steps(), 0).expect("The optimizer trains another model")
+            .save(&path).expect("Failed to save");
+        let json_size = mlp.to_json().expect("Failed to save").len();
+        let checkpoint_size = std::fs::metadata(&path).expect("Failed to save").len();
+        println!("Checkpoint: {} bytes, JSON weights alone: {} bytes", checkpoint_size, json_size);
+    }
+    // A fresh process: different starting weights and an empty optimizer, all overwritten by the checkpoint
+    let mlp = new_model(7);
+    let mut optimizer = Adam::new(mlp.parameters(), 0.001);
+    let checkpoint: Checkpoint<f64> = Checkpoint::load(&path).expect("Failed to load");
+    checkpoint.restore(&mlp, Some(&mut optimizer)).expect("Checkpoint doesn't match the model");
+    println!("Resuming at step {}", checkpoint.step); // Resuming at step 5
+    for _ in 0..5 {
+        train_step(&mlp, &mut optimizer);
+    }
+    let resumed: Vec<u64> = mlp.parameters().iter().map(|p| p.get_data().to_bits()).collect();
+    println!("Final loss: {:.6}", train_step(&mlp, &mut optimizer));
+    println!("Parameters bit-identical: {}", uninterrupted == resumed); // Parameters bit-identical: true
+
+    if let Err(error) = Checkpoint::<f32>::load(&path) {
+        println!("{}", error); // Invalid format: The checkpoint holds f64 values, but the model uses f32
+    }
+    std::fs::remove_file(&path).expect("Failed to clean up");
+}
+
+fn safetensors_test() {
+    println!("\n------ safetensors ------");
+    let mlp: MultiLayerPerceptron<f32> = MultiLayerPerceptron::builder(3)
+        .layers(&[4, 2])
+        .output_activation(Activation::Identity)
+        .build()
+        .expect("Invalid model");
+    let path = std::env::temp_dir().join("micro-grad-mlp.safetensors");
+    mlp.save_safetensors(&path).expect("Failed to save");
+    let bytes = std::fs::read(&path).expect("Failed to read");
+    let header_length = u64::from_le_bytes(bytes[..8].try_into().expect("Too short")) as usize;
+    println!("{}", String::from_utf8_lossy(&bytes[8..8 + header_length]).trim_end());
+    // {"__metadata__":{"activations":"tanh,identity","format":"micro-grad-mlp"},"layers.0.bias":{"data_offsets":[48,64],...
+
+    let loaded: MultiLayerPerceptron<f32> = MultiLayerPerceptron::load_safetensors(&path).expect("Failed to load");
+    let identical = mlp.state_dict().iter().all(|(name, w)| loaded.state_dict()[name].to_bits() == w.to_bits());
+    println!("Weights bit-identical: {}", identical); // Weights bit-identical: true
+    let x = [0.5, -1.0, 2.0];
+    println!("Predictions equal: {}",
+             mlp.predict(&x).expect("Wrong number of inputs") == loaded.predict(&x).expect("Wrong number of inputs"));
+
+    // f32 tensors load exactly into an f64 model
+    let widened: MultiLayerPerceptron<f64> = MultiLayerPerceptron::from_safetensors(&bytes).expect("Failed to load");
+    println!("Widened to f64: {:?}", widened.architecture().layer_num_outputs); // Widened to f64: [4, 2]
+    std::fs::remove_file(&path).expect("Failed to clean up");
+}
+
+fn npy_test() {
+    println!("\n------ Datasets as .npy ------");
+    let xs = NpyArray::from_rows(&[
+        vec![2.0, 3.0, -1.0],
+        vec![3.0, -1.0, 0.5],
+        vec![0.5, 1.0, 1.0],
+        vec![1.0, 1.0, -1.0],
+    ]).expect("Rows differ in length");
+    let path = std::env::temp_dir().join("micro-grad-xs.npy");
+    io::save_npy(&path, &xs).expect("Failed to save");
+    let loaded: NpyArray<f64> = io::load_npy(&path).expect("Failed to load");
+    println!("Loaded shape {:?}, identical: {}", loaded.shape, loaded == xs); // Loaded shape [4, 3], identical: true
+
+    let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, &[4, 4, 1]).expect("Invalid model");
+    let predictions = mlp.predict_batch(&loaded.rows().expect("Not 2-D")).expect("Wrong number of inputs");
+    println!("Predictions: {:.4?}", predictions);
+
+    println!("\n------ Weights as .npz ------");
+    let npz_path = std::env::temp_dir().join("micro-grad-weights.npz");
+    io::save_npz(&npz_path, &mlp.weight_arrays().expect("Some neurons lack a bias")).expect("Failed to save");
+    // In Python: np.load("micro-grad-weights.npz")["layers.0.weight"]
+    for (name, array) in io::load_npz::<f64, _>(&npz_path).expect("Failed to load") {
+        println!("{}: {:?}", name, array.shape); // layers.0.weight: [4, 3]
+    }
+
+    // float32 files widen to f64 exactly
+    let xs32 = NpyArray::new(vec![2, 2], vec![0.1f32, 0.2, 0.3, 0.4]).expect("Wrong number of values");
+    let widened: NpyArray<f64> = io::read_npy(&io::write_npy(&xs32).expect("Failed to write")).expect("Failed to read");
+    println!("{:?}", widened.data); // [0.10000000149011612, 0.20000000298023224, ...]
+    std::fs::remove_file(&path).expect("Failed to clean up");
+    std::fs::remove_file(&npz_path).expect("Failed to clean up");
 }
 
 #[derive(Parser, Debug)]
@@ -215,18 +858,77 @@ struct Args {
     // Action to perform
     #[arg(value_parser)]
     action: String,
+
+    // Panic on the first NaN or infinity in the graph
+    #[arg(long)]
+    detect_anomaly: bool,
 }
 
 fn main() {
     env_logger::init();
     let args = Args::parse();
+    micro_grad::set_detect_anomaly(args.detect_anomaly);
     match args.action.as_str() {
         "tensor" => tensor_test(),
         "nn" => nn_test(),
         "train" => train(),
+        "module" => module_test(),
+        "sequential" => sequential_test(),
+        "optimize" => optimize_test(),
+        "symbolic" => symbolic_test(),
+        "parse" => parse_test(),
+        "repl" => repl(),
+        "anomaly" => anomaly_test(),
+        "parallel" => parallel_test(),
+        "train-parallel" => train_parallel(),
+        "save" => save_test(),
+        "checkpoint" => checkpoint_test(),
+        "safetensors" => safetensors_test(),
+        "npy" => npy_test(),
         _ => {
             eprintln!("Unknown action: {}", args.action);
             std::process::exit(1);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(position: usize, message: &str) -> Result<String, ParseError> {
+        Err(ParseError { position, message: message.to_string() })
+    }
+
+    #[test]
+    fn repl_assigns_and_evaluates() {
+        let mut session = ReplSession::default();
+        assert_eq!(session.evaluate("x = 2.0"), Ok(Scalar::new_with_label(2.0, "x").to_string()));
+        assert!(session.evaluate("  y=x * 3  ").is_ok());
+        assert_eq!(session.variables["y"].get_data(), 6.0);
+        assert_eq!(session.evaluate("x * y + y"), Ok("= 18.0000\n  d/dx = 6.0000\n  d/dy = 3.0000".to_string()));
+        // Gradients start from zero on every evaluation
+        assert_eq!(session.evaluate("x^2"), Ok("= 4.0000\n  d/dx = 4.0000\n  d/dy = 0.0000".to_string()));
+        assert!(session.evaluate(":graph").unwrap().contains("x"));
+        assert_eq!(session.evaluate(":vars").unwrap().lines().count(), 2);
+        assert_eq!(session.evaluate(":reset"), Ok(String::new()));
+        assert_eq!(session.evaluate(":graph"), Ok("No expression yet".to_string()));
+        assert_eq!(session.evaluate(":vars"), Ok(String::new()));
+        assert_eq!(session.evaluate(""), Ok(String::new()));
+        assert_eq!(session.evaluate(":nope"), Ok("Unknown command :nope. Type :help for help.".to_string()));
+    }
+
+    #[test]
+    fn repl_errors_count_characters_from_the_start_of_the_line() {
+        let mut session = ReplSession::default();
+        assert_eq!(session.evaluate("1 + z"), error(4, "Unknown variable z"));
+        assert_eq!(session.evaluate("x = 1 + z"), error(8, "Unknown variable z"));
+        assert_eq!(session.evaluate("  x =1 +"), error(8, "Unexpected end of input"));
+        assert_eq!(session.evaluate("\t1 + z"), error(5, "Unknown variable z"));
+        assert_eq!(session.evaluate("θ = 1 $"), error(6, "Unexpected character '$'"));
+        assert_eq!(session.evaluate("2x = 1"), error(0, "Invalid variable name 2x"));
+        assert_eq!(session.evaluate(" a b = 1"), error(1, "Invalid variable name a b"));
+        assert_eq!(session.evaluate("= 1"), error(0, "Invalid variable name "));
+        assert!(session.variables.is_empty());
+    }
+}
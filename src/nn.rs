@@ -1,92 +1,491 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::marker::PhantomData;
 
 use num_traits::Float;
 use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::Scalar;
+use crate::{Scalar, Var};
+
+// Errors ---------------------------------------------------------------------
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NnError {
+    /// A module was given the wrong number of inputs
+    ShapeMismatch { expected: usize, actual: usize },
+    /// A stage of a `Sequential` expects a different number of inputs than the stage before produces
+    StageMismatch { stage: usize, expected: usize, actual: usize },
+    /// A model with no layers can't be run
+    EmptyModel,
+    InvalidConfig(String),
+    /// A state dict's names don't match the model's parameters
+    StateDictMismatch { missing: Vec<String>, unexpected: Vec<String> },
+    /// Reading or writing a saved model failed
+    Io(String),
+    /// A saved model is malformed, or in a format or version this crate can't read
+    InvalidFormat(String),
+}
+
+impl Display for NnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NnError::ShapeMismatch { expected, actual } => write!(f, "Expected {} inputs, not {}", expected, actual),
+            NnError::StageMismatch { stage, expected, actual } => write!(f,
+                "Stage {} expects {} inputs, but stage {} produces {}", stage, expected, stage - 1, actual),
+            NnError::EmptyModel => write!(f, "The model has no layers"),
+            NnError::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            NnError::StateDictMismatch { missing, unexpected } => write!(f,
+                "State dict doesn't match the model. Missing keys: {:?}, unexpected keys: {:?}", missing, unexpected),
+            NnError::Io(message) => write!(f, "I/O error: {}", message),
+            NnError::InvalidFormat(message) => write!(f, "Invalid format: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for NnError {}
+
+impl From<std::io::Error> for NnError {
+    fn from(error: std::io::Error) -> Self {
+        NnError::Io(error.to_string())
+    }
+}
+
+// A zero-width layer would silently cut the network off from its inputs
+fn check_width(name: &str, size: usize) -> Result<(), NnError> {
+    if size == 0 {
+        Err(NnError::InvalidConfig(format!("{} must be at least 1", name)))?
+    }
+    Ok(())
+}
+
+pub(crate) fn check_num_inputs<T>(expected: usize, inputs: &[T]) -> Result<(), NnError> {
+    if inputs.len() != expected {
+        Err(NnError::ShapeMismatch { expected, actual: inputs.len() })?
+    }
+    Ok(())
+}
+
+// Module ---------------------------------------------------------------------
+
+/// Anything with parameters that maps a list of inputs to a list of outputs. Optimizers, trainers and serializers
+/// work with any `Module`, including ones defined outside the crate.
+pub trait Module<T: Float + Copy + Display + std::ops::AddAssign + 'static> {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError>;
+
+    fn parameters(&self) -> Vec<Scalar<T>>;
+
+    /// Every parameter, with a name that is unique within the module, e.g. `layers.1.neurons.3.w2`
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)>;
+
+    /// The number of inputs the module expects, or None if it takes any number, like an activation
+    fn num_inputs(&self) -> Option<usize> {
+        None
+    }
+
+    /// The number of outputs the module produces from `num_inputs` inputs
+    fn num_outputs(&self, num_inputs: usize) -> usize {
+        num_inputs
+    }
+
+    /// Whether the module is in training mode (the default) or evaluation mode, for modules that behave differently
+    /// in each, such as dropout. Containers pass the mode on to their children.
+    fn is_training(&self) -> bool;
+
+    fn set_training(&mut self, training: bool);
+
+    fn train(&mut self) {
+        self.set_training(true);
+    }
+
+    fn eval(&mut self) {
+        self.set_training(false);
+    }
+
+    fn zero_grad(&self) {
+        for p in self.parameters() {
+            p.zero_grad();
+        }
+    }
+
+    /// Label each parameter with its name, so parameters in different neurons and layers can be told apart
+    fn label_parameters(&self) {
+        for (name, p) in self.named_parameters() {
+            p.set_label(&name);
+        }
+    }
+
+    /// The value of every parameter, by name
+    fn state_dict(&self) -> HashMap<String, T> {
+        self.named_parameters().into_iter().map(|(name, p)| (name, p.get_data())).collect()
+    }
+
+    /// Copy values from `state` into the parameters with the same names. If `strict`, every parameter must have a
+    /// value and every value must have a parameter, or nothing is loaded. Otherwise, the matching values are loaded,
+    /// e.g. to reuse part of another model, and the mismatches are returned.
+    fn load_state_dict(&self, state: &HashMap<String, T>, strict: bool) -> Result<StateDictKeys, NnError> {
+        let named_parameters = self.named_parameters();
+        let missing: Vec<String> = named_parameters.iter()
+            .filter(|(name, _)| !state.contains_key(name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        let names: HashSet<&str> = named_parameters.iter().map(|(name, _)| name.as_str()).collect();
+        let mut unexpected: Vec<String> = state.keys().filter(|name| !names.contains(name.as_str())).cloned().collect();
+        unexpected.sort();
+
+        if strict && !(missing.is_empty() && unexpected.is_empty()) {
+            return Err(NnError::StateDictMismatch { missing, unexpected });
+        }
+        for (name, p) in &named_parameters {
+            if let Some(value) = state.get(name) {
+                p.set_data(*value);
+            }
+        }
+        Ok(StateDictKeys { missing, unexpected })
+    }
+
+    /// The activation the module applies to its outputs, if it applies one of the `Activation`s
+    fn activation(&self) -> Option<Activation> {
+        None
+    }
+
+    /// One row per layer for `summary`, named by joining `prefix` and the layer's name, like parameter names.
+    /// `num_inputs` is how many inputs reach the module, if known, for modules that take any number. Containers
+    /// override this to return their children's rows.
+    fn summary_rows(&self, prefix: &str, num_inputs: Option<usize>) -> Vec<LayerSummary> {
+        let num_inputs = self.num_inputs().or(num_inputs);
+        vec![LayerSummary {
+            name: prefix.to_string(),
+            kind: short_type_name::<Self>(),
+            num_inputs,
+            num_outputs: num_inputs.map(|n| self.num_outputs(n)),
+            activation: self.activation(),
+            num_parameters: self.parameters().len(),
+        }]
+    }
+
+    /// The module's layers, their sizes, activations and parameter counts. Display it for a table.
+    fn summary(&self) -> ModelSummary {
+        ModelSummary { layers: self.summary_rows("", self.num_inputs()), num_parameters: self.parameters().len() }
+    }
+}
+
+/// The names that didn't match when loading a state dict
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StateDictKeys {
+    /// Parameters that had no value, so kept their old one
+    pub missing: Vec<String>,
+    /// Values that had no parameter, so were ignored
+    pub unexpected: Vec<String>,
+}
+
+// e.g. "Layer" for micro_grad::nn::Layer<f64>
+fn short_type_name<M: ?Sized>() -> String {
+    let name = std::any::type_name::<M>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name).to_string()
+}
+
+fn join_names(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() { name.to_string() } else { format!("{}.{}", prefix, name) }
+}
+
+// Prefix each parameter's name with the name of the child that owns it
+fn prefix_names<T>(prefix: &str, named_parameters: Vec<(String, Scalar<T>)>) -> Vec<(String, Scalar<T>)> {
+    named_parameters.into_iter().map(|(name, p)| (format!("{}.{}", prefix, name), p)).collect()
+}
+
+// Activation -----------------------------------------------------------------
+
+/// The function a neuron applies to its weighted sum
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activation {
+    Identity,
+    Tanh,
+    Relu,
+    Sigmoid,
+}
+
+impl Activation {
+    pub fn apply<T: Float + Copy + Display + std::ops::AddAssign + 'static>(&self, x: &Scalar<T>) -> Scalar<T> {
+        match self {
+            Activation::Identity => x.clone(),
+            Activation::Tanh => x.tanh(),
+            Activation::Relu => x.relu(),
+            Activation::Sigmoid => x.mul_number(-T::one()).exp().add_number(T::one()).pow(-T::one()),
+        }
+    }
+
+    pub fn apply_var<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static>(&self, x: &Var<'g, T>) -> Var<'g, T> {
+        match self {
+            Activation::Identity => *x,
+            Activation::Tanh => x.tanh(),
+            Activation::Relu => x.relu(),
+            Activation::Sigmoid => x.mul_number(-T::one()).exp().add_number(T::one()).pow(-T::one()),
+        }
+    }
+
+    pub fn apply_number<T: Float>(&self, x: T) -> T {
+        match self {
+            Activation::Identity => x,
+            Activation::Tanh => x.tanh(),
+            Activation::Relu => x.max(T::zero()),
+            Activation::Sigmoid => T::one() / (T::one() + (-x).exp()),
+        }
+    }
+
+    pub const ALL: [Activation; 4] = [Activation::Identity, Activation::Tanh, Activation::Relu, Activation::Sigmoid];
+
+    /// The lowercase name, as used in saved models
+    pub fn name(&self) -> &'static str {
+        match self {
+            Activation::Identity => "identity",
+            Activation::Tanh => "tanh",
+            Activation::Relu => "relu",
+            Activation::Sigmoid => "sigmoid",
+        }
+    }
+
+    /// The activation called `name`, the inverse of `name()`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|activation| activation.name() == name)
+    }
+}
+
+// Every saved format goes through name() and from_name(), so they all agree on the names
+
+impl Serialize for Activation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Activation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Activation::from_name(&name).ok_or_else(|| serde::de::Error::custom(format!("Unknown activation {}", name)))
+    }
+}
+
+// Initializer ----------------------------------------------------------------
+
+/// How a layer's starting weights are drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Initializer {
+    /// Weights and biases uniform in [-limit, limit)
+    Uniform(f64),
+    /// Glorot/Xavier uniform weights, suited to tanh and sigmoid. Biases start at zero.
+    Xavier,
+    /// He/Kaiming uniform weights, suited to relu. Biases start at zero.
+    He,
+    /// Every weight and bias set to the same value
+    Constant(f64),
+}
+
+impl Default for Initializer {
+    fn default() -> Self {
+        Initializer::Uniform(1.0)
+    }
+}
+
+impl Initializer {
+    fn validate(&self) -> Result<(), NnError> {
+        match self {
+            Initializer::Uniform(limit) if !(limit.is_finite() && *limit > 0.0) => {
+                Err(NnError::InvalidConfig(format!("Uniform initializer limit must be positive, but is {}", limit)))
+            }
+            Initializer::Constant(value) if !value.is_finite() => {
+                Err(NnError::InvalidConfig(format!("Constant initializer value must be finite, but is {}", value)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The starting weights and bias of one neuron, in a layer with `fan_in` inputs and `fan_out` outputs
+    fn sample<T: Float, R: Rng>(&self, fan_in: usize, fan_out: usize, rng: &mut R) -> (Vec<T>, T) {
+        let (limit, bias_limit) = match *self {
+            Initializer::Uniform(limit) => (limit, Some(limit)),
+            Initializer::Xavier => ((6.0 / (fan_in + fan_out) as f64).sqrt(), None),
+            Initializer::He => ((6.0 / fan_in as f64).sqrt(), None),
+            Initializer::Constant(value) => {
+                return (vec![T::from(value).unwrap(); fan_in], T::from(value).unwrap());
+            }
+        };
+        let uniform = Uniform::new(-limit, limit);
+        let weights = (0..fan_in).map(|_| T::from(uniform.sample(rng)).unwrap()).collect();
+        let bias = bias_limit.map_or(0.0, |limit| Uniform::new(-limit, limit).sample(rng));
+        (weights, T::from(bias).unwrap())
+    }
+}
 
 // Neuron ---------------------------------------------------------------------
 
 pub struct Neuron<T> {
     pub weights: Vec<Scalar<T>>,
-    pub bias: Scalar<T>,
+    /// `None` for a neuron built without a bias
+    pub bias: Option<Scalar<T>>,
+    pub activation: Activation,
+    training: bool,
 }
 
 impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Neuron<T> {
-    pub fn new(num_inputs: i32) -> Self {
-        let mut rng = rand::thread_rng();
-        let uniform = Uniform::new(-1.0, 1.0);
-        let weights: Vec<T> = (0..num_inputs).map(|_| T::from(uniform.sample(&mut rng)).unwrap()).collect();
-        let bias = T::from(uniform.sample(&mut rng)).unwrap();
+    pub fn new(num_inputs: usize) -> Result<Self, NnError> {
+        Self::new_with_rng(num_inputs, &mut rand::thread_rng())
+    }
+
+    /// Initialise the weights and bias from `rng`, e.g. a seeded one for reproducible results
+    pub fn new_with_rng<R: Rng>(num_inputs: usize, rng: &mut R) -> Result<Self, NnError> {
+        Self::new_with_config(num_inputs, 1, Activation::Tanh, Initializer::default(), true, rng)
+    }
+
+    // `fan_out` is the width of the neuron's layer, which some initializers scale by
+    pub(crate) fn new_with_config<R: Rng>(num_inputs: usize, fan_out: usize, activation: Activation,
+                                          initializer: Initializer, bias: bool, rng: &mut R) -> Result<Self, NnError> {
+        check_width("Number of inputs", num_inputs)?;
+        initializer.validate()?;
+        let (weights, bias_data): (Vec<T>, T) = initializer.sample(num_inputs, fan_out, rng);
+        Ok(Self::from_values(&weights, if bias { Some(bias_data) } else { None }, activation))
+    }
+
+    pub(crate) fn from_values(weights: &[T], bias: Option<T>, activation: Activation) -> Self {
         Neuron {
             weights: weights.iter().enumerate()
                 .map(|(i, w)| Scalar::new_with_label(*w, format!("w{}", i).as_str()))
                 .collect(),
-            bias: Scalar::new_with_label(bias, "b"),
+            bias: bias.map(|b| Scalar::new_with_label(b, "b")),
+            activation,
+            training: true,
         }
     }
 
-    pub fn forward(&self, inputs: &Vec<Scalar<T>>) -> Result<Scalar<T>, String> {
-        if inputs.len() != self.weights.len() {
-            Err(format!("Expected {} inputs, not {}", self.weights.len(), inputs.len()))?
-        }
+    pub fn forward(&self, inputs: &[Scalar<T>]) -> Result<Scalar<T>, NnError> {
+        check_num_inputs(self.weights.len(), inputs)?;
 
-        // weight * x + bias
-        let mut sum = self.bias.clone();
-        for (input, weight) in inputs.iter().zip(self.weights.iter()) {
-            sum = &sum + &(input * weight);
-        }
-        Ok(sum.tanh())
+        // weight * x + bias, leaving out the bias term if the neuron has none
+        Ok(self.activation.apply(&Scalar::dot(&self.weights, inputs, self.bias.as_ref())))
     }
 
-    pub fn forward_with_numbers(&self, inputs: &Vec<T>) -> Result<Scalar<T>, String> {
-        let inputs = &inputs.iter().map(|i| Scalar::new(i.clone())).collect();
+    pub fn forward_with_numbers(&self, inputs: &[T]) -> Result<Scalar<T>, NnError> {
+        let inputs: Vec<Scalar<T>> = inputs.iter().map(|i| Scalar::new(*i)).collect();
         self.forward(&inputs)
     }
+}
 
-    pub fn parameters(&self) -> Vec<Scalar<T>> {
-        self.weights.iter().chain(std::iter::once(&self.bias)).cloned().collect()
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for Neuron<T> {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        Ok(vec![Neuron::forward(self, inputs)?])
     }
 
-    pub fn zero_grad(&self) {
-        for p in self.parameters() {
-            p.zero_grad();
-        }
+    fn parameters(&self) -> Vec<Scalar<T>> {
+        self.weights.iter().chain(self.bias.iter()).cloned().collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        let weights = self.weights.iter().enumerate().map(|(i, w)| (format!("w{}", i), w.clone()));
+        weights.chain(self.bias.iter().map(|b| ("b".to_string(), b.clone()))).collect()
+    }
+
+    fn num_inputs(&self) -> Option<usize> {
+        Some(self.weights.len())
+    }
+
+    fn num_outputs(&self, _num_inputs: usize) -> usize {
+        1
+    }
+
+    fn activation(&self) -> Option<Activation> {
+        Some(self.activation)
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
     }
 }
 
 // Layer ----------------------------------------------------------------------
 
 pub struct Layer<T> {
-    pub neurons: Vec<Neuron<T>>
+    pub neurons: Vec<Neuron<T>>,
+    training: bool,
 }
 
 impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Layer<T> {
-    pub fn new(num_inputs: i32, num_outputs: i32) -> Self {
-        Layer {
-            neurons: (0..num_outputs).map(|_| Neuron::new(num_inputs)).collect(),
-        }
+    pub fn new(num_inputs: usize, num_outputs: usize) -> Result<Self, NnError> {
+        Self::new_with_rng(num_inputs, num_outputs, &mut rand::thread_rng())
     }
 
-    pub fn forward(&self, inputs: &Vec<Scalar<T>>) -> Vec<Scalar<T>> {
-        let outputs = self.neurons.iter().map(|n| n.forward(inputs).expect("")).collect();
-        outputs
+    pub fn new_with_rng<R: Rng>(num_inputs: usize, num_outputs: usize, rng: &mut R) -> Result<Self, NnError> {
+        Self::new_with_config(num_inputs, num_outputs, Activation::Tanh, Initializer::default(), true, rng)
     }
 
-    pub fn forward_with_numbers(&self, inputs: &Vec<T>) -> Vec<Scalar<T>> {
-        let inputs = &inputs.iter().map(|i| Scalar::new(i.clone())).collect();
-        let outputs = self.neurons.iter().map(|n| n.forward(inputs).expect("")).collect();
-        outputs
+    /// A layer whose neurons apply `activation` instead of tanh. With `Activation::Identity`, this is a plain dense
+    /// (linear) layer.
+    pub fn new_with_activation(num_inputs: usize, num_outputs: usize, activation: Activation) -> Result<Self, NnError> {
+        Self::new_with_config(num_inputs, num_outputs, activation, Initializer::default(), true, &mut rand::thread_rng())
+    }
+
+    pub(crate) fn new_with_config<R: Rng>(num_inputs: usize, num_outputs: usize, activation: Activation,
+                                          initializer: Initializer, bias: bool, rng: &mut R) -> Result<Self, NnError> {
+        check_width("Number of outputs", num_outputs)?;
+        let neurons = (0..num_outputs)
+            .map(|_| Neuron::new_with_config(num_inputs, num_outputs, activation, initializer, bias, rng))
+            .collect::<Result<_, _>>()?;
+        Ok(Layer::from_neurons(neurons))
+    }
+
+    pub(crate) fn from_neurons(neurons: Vec<Neuron<T>>) -> Self {
+        let layer = Layer { neurons, training: true };
+        layer.label_parameters();
+        layer
+    }
+
+    pub fn forward_with_numbers(&self, inputs: &[T]) -> Result<Vec<Scalar<T>>, NnError> {
+        let inputs: Vec<Scalar<T>> = inputs.iter().map(|i| Scalar::new(*i)).collect();
+        self.neurons.iter().map(|n| n.forward(&inputs)).collect()
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for Layer<T> {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        self.neurons.iter().map(|n| n.forward(inputs)).collect()
     }
 
-    pub fn parameters(&self) -> Vec<Scalar<T>> {
+    fn parameters(&self) -> Vec<Scalar<T>> {
         self.neurons.iter().flat_map(|n| n.parameters()).collect()
     }
 
-    pub fn zero_grad(&self) {
-        for p in self.parameters() {
-            p.zero_grad();
-        }
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        self.neurons.iter().enumerate()
+            .flat_map(|(i, n)| prefix_names(&format!("neurons.{}", i), n.named_parameters()))
+            .collect()
+    }
+
+    fn num_inputs(&self) -> Option<usize> {
+        self.neurons.first().map(|n| n.weights.len())
+    }
+
+    fn num_outputs(&self, _num_inputs: usize) -> usize {
+        self.neurons.len()
+    }
+
+    fn activation(&self) -> Option<Activation> {
+        self.neurons.first().map(|n| n.activation)
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        self.neurons.iter_mut().for_each(|n| n.set_training(training));
     }
 }
 
@@ -94,35 +493,1081 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Layer<T> {
 
 pub struct MultiLayerPerceptron<T> {
     pub layers: Vec<Layer<T>>,
+    training: bool,
 }
 
 impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> MultiLayerPerceptron<T> {
-    pub fn new(num_inputs: i32, layer_num_outputs: Vec<i32>) -> Self {
-        let mut num_inputs = num_inputs;
-        MultiLayerPerceptron {
-            layers: layer_num_outputs.iter().map(|num_outputs| {
-                let layer = Layer::new(num_inputs, *num_outputs);
-                num_inputs = *num_outputs;
-                layer
-            }).collect(),
+    pub fn new(num_inputs: usize, layer_num_outputs: &[usize]) -> Result<Self, NnError> {
+        Self::builder(num_inputs).layers(layer_num_outputs).build()
+    }
+
+    pub fn new_with_rng<R: Rng>(num_inputs: usize, layer_num_outputs: &[usize], rng: &mut R) -> Result<Self, NnError> {
+        Self::builder(num_inputs).layers(layer_num_outputs).build_with_rng(rng)
+    }
+
+    /// Assemble an MLP from existing layers, checking that each layer takes as many inputs as the one before produces
+    pub(crate) fn from_layers(layers: Vec<Layer<T>>) -> Result<Self, NnError> {
+        if layers.is_empty() {
+            Err(NnError::EmptyModel)?
         }
+        for (i, layer) in layers.iter().enumerate() {
+            if layer.neurons.is_empty() {
+                Err(NnError::InvalidConfig(format!("Layer {} has zero width", i)))?
+            }
+            let expected = layer.neurons[0].weights.len();
+            check_width("Number of inputs", expected)?;
+            if layer.neurons.iter().any(|n| n.weights.len() != expected) {
+                Err(NnError::InvalidConfig(format!("Layer {} has neurons with different numbers of inputs", i)))?
+            }
+            if i > 0 && expected != layers[i - 1].neurons.len() {
+                Err(NnError::StageMismatch { stage: i, expected, actual: layers[i - 1].neurons.len() })?
+            }
+        }
+        let mlp = MultiLayerPerceptron { layers, training: true };
+        mlp.label_parameters();
+        Ok(mlp)
     }
 
-    pub fn forward(&self, inputs: &Vec<T>) -> Vec<Scalar<T>> {
-        let mut hidden_layer = self.layers[0].forward_with_numbers(inputs);
-        for layer in self.layers.iter().skip(1) {
-            hidden_layer = layer.forward(&hidden_layer);
+    /// Configure an MLP with more control than `new`, e.g.
+    /// `MultiLayerPerceptron::builder(3).layers(&[16, 1]).activation(Activation::Relu).build()`
+    pub fn builder(num_inputs: usize) -> MlpBuilder<T> {
+        MlpBuilder {
+            num_inputs,
+            layer_num_outputs: Vec::new(),
+            activation: Activation::Tanh,
+            output_activation: None,
+            initializer: Initializer::default(),
+            bias: true,
+            seed: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn forward(&self, inputs: &[T]) -> Result<Vec<Scalar<T>>, NnError> {
+        let inputs: Vec<Scalar<T>> = inputs.iter().map(|i| Scalar::new(*i)).collect();
+        self.forward_scalars(&inputs)
+    }
+
+    /// Run the network on each sample, keeping the outputs of each sample together
+    pub fn forward_batch(&self, batch: &[Vec<T>]) -> Result<Vec<Vec<Scalar<T>>>, NnError> {
+        batch.iter().map(|inputs| self.forward(inputs)).collect()
+    }
+
+    /// Evaluate the network on plain numbers, without building a graph, for when no gradients are needed
+    pub fn predict(&self, inputs: &[T]) -> Result<Vec<T>, NnError> {
+        if self.layers.is_empty() {
+            Err(NnError::EmptyModel)?
+        }
+        let mut hidden_layer = inputs.to_vec();
+        for layer in &self.layers {
+            hidden_layer = layer.neurons.iter().map(|n| {
+                check_num_inputs(n.weights.len(), &hidden_layer)?;
+                let mut sum = n.bias.as_ref().map_or(T::zero(), |b| b.get_data());
+                for (weight, input) in n.weights.iter().zip(hidden_layer.iter()) {
+                    sum += weight.get_data() * *input;
+                }
+                Ok(n.activation.apply_number(sum))
+            }).collect::<Result<_, NnError>>()?;
         }
-        hidden_layer
+        Ok(hidden_layer)
     }
 
-    pub fn parameters(&self) -> Vec<Scalar<T>> {
+    pub fn predict_batch(&self, batch: &[Vec<T>]) -> Result<Vec<Vec<T>>, NnError> {
+        batch.iter().map(|inputs| self.predict(inputs)).collect()
+    }
+
+    /// Run the network on inputs that may come out of another computation. Gradients flow back through `inputs`,
+    /// so after `backward`, their grads say how sensitive the outputs are to each input.
+    pub fn forward_scalars(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        if self.layers.is_empty() {
+            Err(NnError::EmptyModel)?
+        }
+        let mut hidden_layer = inputs.to_vec();
+        for layer in &self.layers {
+            hidden_layer = layer.forward(&hidden_layer)?;
+        }
+        Ok(hidden_layer)
+    }
+
+    pub fn architecture(&self) -> Architecture {
+        Architecture {
+            num_inputs: self.layers.first().and_then(|l| l.neurons.first()).map_or(0, |n| n.weights.len()),
+            layer_num_outputs: self.layers.iter().map(|l| l.neurons.len()).collect(),
+            layer_activations: self.layers.iter()
+                .map(|l| l.neurons.first().map_or(Activation::Tanh, |n| n.activation))
+                .collect(),
+            layer_biases: self.layers.iter()
+                .map(|l| l.neurons.first().is_some_and(|n| n.bias.is_some()))
+                .collect(),
+        }
+    }
+}
+
+/// Collects an MLP's configuration, then checks it all at once in `build`
+pub struct MlpBuilder<T> {
+    num_inputs: usize,
+    layer_num_outputs: Vec<usize>,
+    activation: Activation,
+    output_activation: Option<Activation>,
+    initializer: Initializer,
+    bias: bool,
+    seed: Option<u64>,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> MlpBuilder<T> {
+    /// Append a layer with `num_outputs` neurons
+    pub fn layer(mut self, num_outputs: usize) -> Self {
+        self.layer_num_outputs.push(num_outputs);
+        self
+    }
+
+    pub fn layers(mut self, layer_num_outputs: &[usize]) -> Self {
+        self.layer_num_outputs.extend_from_slice(layer_num_outputs);
+        self
+    }
+
+    /// The activation of every layer, except the last if `output_activation` is set. Defaults to tanh.
+    pub fn activation(mut self, activation: Activation) -> Self {
+        self.activation = activation;
+        self
+    }
+
+    pub fn output_activation(mut self, activation: Activation) -> Self {
+        self.output_activation = Some(activation);
+        self
+    }
+
+    pub fn initializer(mut self, initializer: Initializer) -> Self {
+        self.initializer = initializer;
+        self
+    }
+
+    /// Whether the neurons have a bias. Defaults to true.
+    pub fn bias(mut self, bias: bool) -> Self {
+        self.bias = bias;
+        self
+    }
+
+    /// Draw the starting weights from an RNG seeded with `seed`, for reproducible results
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> Result<MultiLayerPerceptron<T>, NnError> {
+        match self.seed {
+            Some(seed) => self.build_with_rng(&mut StdRng::seed_from_u64(seed)),
+            None => self.build_with_rng(&mut rand::thread_rng()),
+        }
+    }
+
+    /// Like `build`, but draws the starting weights from `rng` instead of the builder's seed
+    pub fn build_with_rng<R: Rng>(self, rng: &mut R) -> Result<MultiLayerPerceptron<T>, NnError> {
+        check_width("Number of inputs", self.num_inputs)?;
+        if self.layer_num_outputs.is_empty() {
+            Err(NnError::EmptyModel)?
+        }
+        if let Some(i) = self.layer_num_outputs.iter().position(|n| *n == 0) {
+            Err(NnError::InvalidConfig(format!("Layer {} has zero width", i)))?
+        }
+
+        let mut num_inputs = self.num_inputs;
+        let last = self.layer_num_outputs.len() - 1;
+        let layers = self.layer_num_outputs.iter().enumerate().map(|(i, num_outputs)| {
+            let activation = if i == last { self.output_activation.unwrap_or(self.activation) } else { self.activation };
+            let layer = Layer::new_with_config(num_inputs, *num_outputs, activation, self.initializer, self.bias, rng);
+            num_inputs = *num_outputs;
+            layer
+        }).collect::<Result<_, _>>()?;
+        MultiLayerPerceptron::from_layers(layers)
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for MultiLayerPerceptron<T> {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        self.forward_scalars(inputs)
+    }
+
+    fn parameters(&self) -> Vec<Scalar<T>> {
         self.layers.iter().flat_map(|n| n.parameters()).collect()
     }
 
-    pub fn zero_grad(&self) {
-        for p in self.parameters() {
-            p.zero_grad();
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        self.layers.iter().enumerate()
+            .flat_map(|(i, l)| prefix_names(&format!("layers.{}", i), l.named_parameters()))
+            .collect()
+    }
+
+    fn num_inputs(&self) -> Option<usize> {
+        self.layers.first().and_then(|l| l.num_inputs())
+    }
+
+    fn num_outputs(&self, num_inputs: usize) -> usize {
+        self.layers.last().map_or(num_inputs, |l| l.neurons.len())
+    }
+
+    fn summary_rows(&self, prefix: &str, _num_inputs: Option<usize>) -> Vec<LayerSummary> {
+        self.layers.iter().enumerate()
+            .flat_map(|(i, l)| l.summary_rows(&join_names(prefix, &format!("layers.{}", i)), None))
+            .collect()
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        self.layers.iter_mut().for_each(|l| l.set_training(training));
+    }
+}
+
+// Activation layer -----------------------------------------------------------
+
+/// Applies an activation to each input separately, e.g. after a dense layer in a `Sequential`
+pub struct ActivationLayer {
+    pub activation: Activation,
+    training: bool,
+}
+
+impl ActivationLayer {
+    pub fn new(activation: Activation) -> Self {
+        ActivationLayer { activation, training: true }
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for ActivationLayer {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        Ok(inputs.iter().map(|x| self.activation.apply(x)).collect())
+    }
+
+    fn activation(&self) -> Option<Activation> {
+        Some(self.activation)
+    }
+
+    fn parameters(&self) -> Vec<Scalar<T>> {
+        Vec::new()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        Vec::new()
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+// Softmax --------------------------------------------------------------------
+
+/// Turns the inputs into probabilities that sum to one
+pub struct Softmax {
+    training: bool,
+}
+
+impl Softmax {
+    pub fn new() -> Self {
+        Softmax { training: true }
+    }
+}
+
+impl Default for Softmax {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for Softmax {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        // Shifting by the largest input doesn't change the result, but keeps exp from overflowing
+        let max = inputs.iter().map(|x| x.get_data()).fold(T::neg_infinity(), T::max);
+        let exps: Vec<Scalar<T>> = inputs.iter().map(|x| x.add_number(-max).exp()).collect();
+        let mut sum = Scalar::constant(T::zero());
+        for e in &exps {
+            sum = &sum + e;
+        }
+        Ok(exps.iter().map(|e| e / &sum).collect())
+    }
+
+    fn parameters(&self) -> Vec<Scalar<T>> {
+        Vec::new()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        Vec::new()
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+// Dropout --------------------------------------------------------------------
+
+/// In training mode, zeroes each input with probability `p` and scales the rest by `1 / (1 - p)`. In evaluation
+/// mode, passes the inputs through unchanged.
+pub struct Dropout {
+    pub p: f64,
+    rng: RefCell<StdRng>,
+    training: bool,
+}
+
+impl Dropout {
+    pub fn new(p: f64) -> Result<Self, NnError> {
+        Self::new_with_rng(p, StdRng::from_entropy())
+    }
+
+    pub fn new_with_rng(p: f64, rng: StdRng) -> Result<Self, NnError> {
+        if !(0.0..1.0).contains(&p) {
+            Err(NnError::InvalidConfig(format!("Dropout probability must be in [0, 1), but is {}", p)))?
+        }
+        Ok(Dropout { p, rng: RefCell::new(rng), training: true })
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for Dropout {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        if !self.training || self.p <= 0.0 {
+            return Ok(inputs.to_vec());
+        }
+        let scale = T::from(1.0 / (1.0 - self.p)).unwrap();
+        let mut rng = self.rng.borrow_mut();
+        Ok(inputs.iter().map(|x| {
+            let keep = rng.gen::<f64>() >= self.p;
+            x.mul_number(if keep { scale } else { T::zero() })
+        }).collect())
+    }
+
+    fn parameters(&self) -> Vec<Scalar<T>> {
+        Vec::new()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        Vec::new()
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+// Layer normalization --------------------------------------------------------
+
+/// Normalises the inputs to zero mean and unit variance, then applies a learned scale (`gamma`) and shift (`beta`)
+/// to each
+pub struct LayerNorm<T> {
+    pub gamma: Vec<Scalar<T>>,
+    pub beta: Vec<Scalar<T>>,
+    pub epsilon: T,
+    training: bool,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> LayerNorm<T> {
+    pub fn new(num_inputs: usize) -> Result<Self, NnError> {
+        check_width("Number of inputs", num_inputs)?;
+        Ok(LayerNorm {
+            gamma: (0..num_inputs).map(|i| Scalar::new_with_label(T::one(), &format!("gamma{}", i))).collect(),
+            beta: (0..num_inputs).map(|i| Scalar::new_with_label(T::zero(), &format!("beta{}", i))).collect(),
+            epsilon: T::from(1e-5).unwrap(),
+            training: true,
+        })
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for LayerNorm<T> {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        check_num_inputs(self.gamma.len(), inputs)?;
+        let n = T::from(inputs.len()).unwrap();
+        let mut sum = Scalar::constant(T::zero());
+        for x in inputs {
+            sum = &sum + x;
         }
+        let mean = sum.mul_number(T::one() / n);
+        let centred: Vec<Scalar<T>> = inputs.iter().map(|x| x - &mean).collect();
+        let mut squares = Scalar::constant(T::zero());
+        for c in &centred {
+            squares = &squares + &c.pow(T::from(2).unwrap());
+        }
+        let inverse_std = squares.mul_number(T::one() / n).add_number(self.epsilon).pow(T::from(-0.5).unwrap());
+        Ok(centred.iter().zip(self.gamma.iter().zip(self.beta.iter()))
+            .map(|(c, (gamma, beta))| &(&(c * &inverse_std) * gamma) + beta)
+            .collect())
+    }
+
+    fn parameters(&self) -> Vec<Scalar<T>> {
+        self.gamma.iter().chain(self.beta.iter()).cloned().collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        let gamma = self.gamma.iter().enumerate().map(|(i, g)| (format!("gamma{}", i), g.clone()));
+        let beta = self.beta.iter().enumerate().map(|(i, b)| (format!("beta{}", i), b.clone()));
+        gamma.chain(beta).collect()
+    }
+
+    fn num_inputs(&self) -> Option<usize> {
+        Some(self.gamma.len())
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+}
+
+// Sequential -----------------------------------------------------------------
+
+/// Runs modules one after the other, each taking the previous one's outputs as its inputs
+pub struct Sequential<T> {
+    pub modules: Vec<Box<dyn Module<T>>>,
+    training: bool,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Sequential<T> {
+    /// Fails if a module's outputs don't match the number of inputs the next module expects. Modules that take any
+    /// number of inputs are assumed to produce as many outputs as they are given.
+    pub fn new(modules: Vec<Box<dyn Module<T>>>) -> Result<Self, NnError> {
+        let mut num_outputs: Option<usize> = None;
+        for (i, module) in modules.iter().enumerate() {
+            num_outputs = match (num_outputs, module.num_inputs()) {
+                (Some(actual), Some(expected)) if actual != expected => {
+                    Err(NnError::StageMismatch { stage: i, expected, actual })?
+                }
+                (_, Some(expected)) => Some(module.num_outputs(expected)),
+                (Some(actual), None) => Some(module.num_outputs(actual)),
+                (None, None) => None,
+            };
+        }
+        let sequential = Sequential { modules, training: true };
+        sequential.label_parameters();
+        Ok(sequential)
+    }
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Module<T> for Sequential<T> {
+    fn forward(&self, inputs: &[Scalar<T>]) -> Result<Vec<Scalar<T>>, NnError> {
+        let mut hidden = inputs.to_vec();
+        for module in &self.modules {
+            hidden = module.forward(&hidden)?;
+        }
+        Ok(hidden)
+    }
+
+    fn parameters(&self) -> Vec<Scalar<T>> {
+        self.modules.iter().flat_map(|m| m.parameters()).collect()
+    }
+
+    fn named_parameters(&self) -> Vec<(String, Scalar<T>)> {
+        self.modules.iter().enumerate()
+            .flat_map(|(i, m)| prefix_names(&i.to_string(), m.named_parameters()))
+            .collect()
+    }
+
+    fn num_inputs(&self) -> Option<usize> {
+        self.modules.iter().find_map(|m| m.num_inputs())
+    }
+
+    fn num_outputs(&self, num_inputs: usize) -> usize {
+        self.modules.iter().fold(num_inputs, |n, m| m.num_outputs(n))
+    }
+
+    fn summary_rows(&self, prefix: &str, num_inputs: Option<usize>) -> Vec<LayerSummary> {
+        let mut width = num_inputs;
+        let mut rows = Vec::new();
+        for (i, module) in self.modules.iter().enumerate() {
+            rows.extend(module.summary_rows(&join_names(prefix, &i.to_string()), width));
+            width = module.num_inputs().or(width).map(|n| module.num_outputs(n));
+        }
+        rows
+    }
+
+    fn is_training(&self) -> bool {
+        self.training
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+        self.modules.iter_mut().for_each(|m| m.set_training(training));
+    }
+}
+
+// Summary --------------------------------------------------------------------
+
+/// One layer of a `ModelSummary`. Sizes are None where they depend on inputs the summary can't know about.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerSummary {
+    pub name: String,
+    /// The module's type, e.g. `Layer` or `Dropout`
+    pub kind: String,
+    pub num_inputs: Option<usize>,
+    pub num_outputs: Option<usize>,
+    pub activation: Option<Activation>,
+    pub num_parameters: usize,
+}
+
+/// What `Module::summary` returns: the model's layers, plus its total number of trainable parameters
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelSummary {
+    pub layers: Vec<LayerSummary>,
+    pub num_parameters: usize,
+}
+
+impl Display for ModelSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let size = |size: Option<usize>| size.map_or("?".to_string(), |s| s.to_string());
+        let header = ["Layer", "Type", "Inputs", "Outputs", "Activation", "Parameters"].map(String::from);
+        let rows: Vec<[String; 6]> = self.layers.iter().map(|l| [
+            if l.name.is_empty() { "-".to_string() } else { l.name.clone() },
+            l.kind.clone(),
+            size(l.num_inputs),
+            size(l.num_outputs),
+            l.activation.map_or("-", |a| a.name()).to_string(),
+            l.num_parameters.to_string(),
+        ]).collect();
+
+        let mut widths = header.clone().map(|h| h.len());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.len());
+            }
+        }
+        let line = "-".repeat(widths.iter().sum::<usize>() + 2 * (widths.len() - 1));
+        let format_row = |row: &[String; 6]| -> String {
+            // Text columns are left aligned, numbers right aligned
+            let cells: Vec<String> = row.iter().zip(widths.iter()).enumerate().map(|(i, (cell, width))| {
+                if i < 2 || i == 4 { format!("{:<1$}", cell, width) } else { format!("{:>1$}", cell, width) }
+            }).collect();
+            cells.join("  ").trim_end().to_string()
+        };
+
+        writeln!(f, "{}", format_row(&header))?;
+        writeln!(f, "{}", line)?;
+        for row in &rows {
+            writeln!(f, "{}", format_row(row))?;
+        }
+        writeln!(f, "{}", line)?;
+        write!(f, "Trainable parameters: {}", self.num_parameters)
+    }
+}
+
+// Architecture ---------------------------------------------------------------
+
+/// The shape of a `MultiLayerPerceptron`, without its parameters. Unlike the model, this can be shared between
+/// threads, and used to run the same network in an arena `Graph`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Architecture {
+    pub num_inputs: usize,
+    pub layer_num_outputs: Vec<usize>,
+    pub layer_activations: Vec<Activation>,
+    pub layer_biases: Vec<bool>,
+}
+
+impl Architecture {
+    // The fields are public, so they may not describe a network that `MultiLayerPerceptron` would build
+    fn check(&self) -> Result<(), NnError> {
+        check_width("Number of inputs", self.num_inputs)?;
+        if self.layer_num_outputs.is_empty() {
+            Err(NnError::EmptyModel)?
+        }
+        if let Some(i) = self.layer_num_outputs.iter().position(|n| *n == 0) {
+            Err(NnError::InvalidConfig(format!("Layer {} has zero width", i)))?
+        }
+        if self.layer_activations.len() != self.layer_num_outputs.len()
+            || self.layer_biases.len() != self.layer_num_outputs.len() {
+            Err(NnError::InvalidConfig("Every layer needs a number of outputs, an activation and a bias".into()))?
+        }
+        Ok(())
+    }
+
+    pub fn num_parameters(&self) -> usize {
+        let mut num_inputs = self.num_inputs;
+        self.layer_num_outputs.iter().zip(self.layer_biases.iter()).map(|(num_outputs, bias)| {
+            let count = (num_inputs + usize::from(*bias)) * num_outputs;
+            num_inputs = *num_outputs;
+            count
+        }).sum()
+    }
+
+    /// Run the network in an arena graph. `parameters` must be in the same order as
+    /// `MultiLayerPerceptron::parameters`, e.g. bound by `SharedParameters::bind`, and there must be exactly
+    /// `num_parameters` of them.
+    pub fn forward_var<'g, T: Float + Copy + Display + std::ops::AddAssign + 'static>(
+        &self, parameters: &[Var<'g, T>], inputs: &[Var<'g, T>]) -> Result<Vec<Var<'g, T>>, NnError> {
+        self.check()?;
+        check_num_inputs(self.num_inputs, inputs)?;
+        if parameters.len() != self.num_parameters() {
+            Err(NnError::ShapeMismatch { expected: self.num_parameters(), actual: parameters.len() })?
+        }
+        let mut parameters = parameters.iter();
+        let mut hidden_layer = inputs.to_vec();
+        let layers = self.layer_num_outputs.iter().zip(self.layer_activations.iter()).zip(self.layer_biases.iter());
+        for ((num_outputs, activation), bias) in layers {
+            hidden_layer = (0..*num_outputs).map(|_| {
+                // The counts are checked above, and every layer has at least one input
+                let weights: Vec<&Var<T>> = parameters.by_ref().take(hidden_layer.len()).collect();
+                let bias = if *bias { parameters.next().copied() } else { None };
+                let mut products = hidden_layer.iter().zip(weights).map(|(input, weight)| input * weight);
+                let mut sum = bias.or_else(|| products.next()).unwrap();
+                for product in products {
+                    sum = &sum + &product;
+                }
+                activation.apply_var(&sum)
+            }).collect();
+        }
+        Ok(hidden_layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Graph;
+
+    const INPUTS: [f64; 3] = [0.5, -1.5, 2.0];
+
+    // Every activation in the hidden layers and a different one at the output, with and without biases
+    fn models() -> Vec<MultiLayerPerceptron<f64>> {
+        Activation::ALL.iter().enumerate().flat_map(|(i, activation)| [true, false].map(|bias| {
+            MultiLayerPerceptron::builder(INPUTS.len())
+                .layers(&[4, 3, 2])
+                .activation(*activation)
+                .output_activation(Activation::ALL[(i + 1) % Activation::ALL.len()])
+                .bias(bias)
+                .seed(i as u64)
+                .build()
+                .expect("Invalid model")
+        })).collect()
+    }
+
+    #[test]
+    fn neurons_without_a_bias_leave_out_the_bias_term() {
+        let x = Scalar::new_with_label(3.0, "x");
+        let y = Scalar::new_with_label(0.5, "y");
+        let neuron = Neuron::from_values(&[2.0, -1.0], None, Activation::Identity);
+        let output = neuron.forward(&[x.clone(), y.clone()]).unwrap();
+        assert_eq!(output.get_data(), 5.5);
+        assert_eq!(output.producers().len(), 4);
+        assert_eq!(crate::Expr::from_scalar(&output).unwrap().to_string(), "w0 * x + w1 * y");
+
+        let mut tape = crate::Tape::trace(&output, &[("x", &x), ("y", &y)], &neuron.parameters()).unwrap();
+        tape.set_input("x", -1.0).unwrap();
+        assert_eq!(tape.forward(), -2.5);
+        tape.backward();
+        assert_eq!(tape.grad("x"), Some(2.0));
+        let grads: Vec<f64> = tape.parameter_grads().map(|(_, grad)| grad).collect();
+        assert_eq!(grads, [-1.0, 0.5]);
+    }
+
+    #[test]
+    fn layers_with_an_activation_use_it_for_every_neuron() {
+        let layer: Layer<f64> = Layer::new_with_activation(3, 4, Activation::Relu).unwrap();
+        assert!(layer.neurons.iter().all(|n| n.activation == Activation::Relu && n.bias.is_some()));
+        assert_eq!(layer.parameters().len(), 16);
+    }
+
+    // The sums are added in the same order, but the activations aren't computed the same way: the graph's sigmoid
+    // takes a power of -1 where predict divides, so the results can differ in the last bit
+    #[test]
+    fn predict_matches_forward() {
+        for mlp in models() {
+            let outputs: Vec<f64> = mlp.forward(&INPUTS).unwrap().iter().map(|o| o.get_data()).collect();
+            for (prediction, output) in mlp.predict(&INPUTS).unwrap().iter().zip(&outputs) {
+                assert!((prediction - output).abs() <= 1e-12 * output.abs().max(1.0), "{:?}", mlp.architecture());
+            }
+        }
+    }
+
+    #[test]
+    fn gradients_flow_through_forward_scalars_into_another_model() {
+        let first: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(3).layers(&[4, 2]).seed(1).build()
+            .expect("Invalid model");
+        let second: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(2).layers(&[3, 1])
+            .activation(Activation::Sigmoid).seed(2).build().expect("Invalid model");
+        let chained = |x: &[f64]| second.predict(&first.predict(x).unwrap()).unwrap()[0];
+
+        let inputs = scalars(&INPUTS);
+        let hidden = first.forward_scalars(&inputs).unwrap();
+        let output = &second.forward_scalars(&hidden).unwrap()[0];
+        assert!((output.get_data() - chained(&INPUTS)).abs() < 1e-12);
+        output.backward();
+
+        // Central differences
+        let h = 1e-6;
+        for (i, input) in inputs.iter().enumerate() {
+            let (mut above, mut below) = (INPUTS, INPUTS);
+            above[i] += h;
+            below[i] -= h;
+            let expected = (chained(&above) - chained(&below)) / (2.0 * h);
+            assert!(input.get_grad() != 0.0);
+            assert!((input.get_grad() - expected).abs() < 1e-6, "{}: {} != {}", i, input.get_grad(), expected);
+        }
+        assert!(first.parameters().iter().any(|p| p.get_grad() != 0.0));
+    }
+
+    #[test]
+    fn set_training_reaches_nested_modules() {
+        let mut mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, &[4, 2]).expect("Invalid model");
+        mlp.eval();
+        assert!(!mlp.is_training());
+        assert!(mlp.layers.iter().all(|l| !l.is_training() && l.neurons.iter().all(|n| !n.is_training())));
+        mlp.train();
+        assert!(mlp.layers.iter().all(|l| l.is_training() && l.neurons.iter().all(|n| n.is_training())));
+
+        let inner: Sequential<f64> = Sequential::new(vec![
+            Box::new(Dropout::new_with_rng(0.5, StdRng::seed_from_u64(0)).expect("Invalid dropout")),
+            Box::new(LayerNorm::new(2).expect("Invalid layer norm")),
+        ]).expect("Invalid model");
+        let mut model = Sequential::new(vec![Box::new(mlp), Box::new(inner)]).expect("Invalid model");
+        model.eval();
+        assert!(!model.is_training() && model.modules.iter().all(|m| !m.is_training()));
+        // The dropout two levels down passes its inputs through, so every run gives the same outputs
+        let inputs: Vec<Scalar<f64>> = INPUTS.iter().map(|x| Scalar::new(*x)).collect();
+        let outputs = |model: &Sequential<f64>| -> Vec<f64> {
+            model.forward(&inputs).unwrap().iter().map(|o| o.get_data()).collect()
+        };
+        let first = outputs(&model);
+        assert!((0..10).all(|_| outputs(&model) == first));
+        model.train();
+        assert!(model.is_training() && model.modules.iter().all(|m| m.is_training()));
+        assert!((0..10).any(|_| outputs(&model) != first));
+    }
+
+    #[test]
+    fn named_parameters_are_unique_and_in_parameter_order() {
+        let sequential: Sequential<f64> = Sequential::new(vec![
+            Box::new(Layer::new(3, 4).expect("Invalid layer")),
+            Box::new(LayerNorm::new(4).expect("Invalid layer norm")),
+            Box::new(Sequential::new(vec![
+                Box::new(ActivationLayer::new(Activation::Relu)),
+                Box::new(Layer::new(4, 2).expect("Invalid layer")),
+            ]).expect("Invalid model")),
+        ]).expect("Invalid model");
+        let mut modules: Vec<Box<dyn Module<f64>>> = models().into_iter()
+            .map(|mlp| Box::new(mlp) as Box<dyn Module<f64>>)
+            .collect();
+        modules.push(Box::new(sequential));
+        for module in &modules {
+            let named_parameters = module.named_parameters();
+            let names: HashSet<&String> = named_parameters.iter().map(|(name, _)| name).collect();
+            assert_eq!(names.len(), named_parameters.len());
+            let ids: Vec<usize> = module.parameters().iter().map(|p| p.id()).collect();
+            assert_eq!(named_parameters.iter().map(|(_, p)| p.id()).collect::<Vec<usize>>(), ids);
+        }
+        let names: Vec<String> = modules[8].named_parameters().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(&names[..5], ["0.neurons.0.w0", "0.neurons.0.w1", "0.neurons.0.w2", "0.neurons.0.b",
+                                 "0.neurons.1.w0"]);
+        assert_eq!(names[16], "1.gamma0");
+        assert_eq!(names[24], "2.1.neurons.0.w0");
+    }
+
+    #[test]
+    fn wrong_numbers_of_inputs_are_shape_mismatches() {
+        let layer: Layer<f64> = Layer::new(3, 2).expect("Invalid layer");
+        assert_eq!(Module::forward(&layer, &scalars(&[1.0, 2.0])).err(),
+                   Some(NnError::ShapeMismatch { expected: 3, actual: 2 }));
+        assert_eq!(layer.forward_with_numbers(&[1.0, 2.0, 3.0, 4.0]).err(),
+                   Some(NnError::ShapeMismatch { expected: 3, actual: 4 }));
+
+        let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, &[4, 2]).expect("Invalid model");
+        let error = mlp.forward(&[1.0]).err().unwrap();
+        assert_eq!(error, NnError::ShapeMismatch { expected: 3, actual: 1 });
+        assert_eq!(error.to_string(), "Expected 3 inputs, not 1");
+        assert_eq!(mlp.forward(&[]).err(), Some(NnError::ShapeMismatch { expected: 3, actual: 0 }));
+        assert_eq!(mlp.predict(&[1.0; 5]).err(), Some(NnError::ShapeMismatch { expected: 3, actual: 5 }));
+        assert_eq!(mlp.forward_batch(&[INPUTS.to_vec(), vec![1.0]]).err(),
+                   Some(NnError::ShapeMismatch { expected: 3, actual: 1 }));
+    }
+
+    #[test]
+    fn empty_models_and_zero_widths_are_errors() {
+        let no_layers = MultiLayerPerceptron::<f64>::new(3, &[]).err();
+        assert_eq!(no_layers, Some(NnError::EmptyModel));
+        assert_eq!(MultiLayerPerceptron::<f64>::from_layers(Vec::new()).err(), Some(NnError::EmptyModel));
+        let mut mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, &[2]).expect("Invalid model");
+        mlp.layers.clear();
+        assert_eq!(mlp.forward(&INPUTS).err(), Some(NnError::EmptyModel));
+        assert_eq!(mlp.predict(&INPUTS).err(), Some(NnError::EmptyModel));
+
+        let invalid = |message: &str| Some(NnError::InvalidConfig(message.to_string()));
+        assert_eq!(MultiLayerPerceptron::<f64>::new(3, &[4, 0, 1]).err(), invalid("Layer 1 has zero width"));
+        assert_eq!(MultiLayerPerceptron::<f64>::new(0, &[4, 1]).err(), invalid("Number of inputs must be at least 1"));
+        assert_eq!(Layer::<f64>::new(3, 0).err(), invalid("Number of outputs must be at least 1"));
+        assert_eq!(Layer::<f64>::new(0, 3).err(), invalid("Number of inputs must be at least 1"));
+        assert_eq!(Neuron::<f64>::new(0).err(), invalid("Number of inputs must be at least 1"));
+        assert_eq!(LayerNorm::<f64>::new(0).err(), invalid("Number of inputs must be at least 1"));
+        let empty_layer: Layer<f64> = Layer::from_neurons(Vec::new());
+        assert_eq!(MultiLayerPerceptron::from_layers(vec![empty_layer]).err(), invalid("Layer 0 has zero width"));
+    }
+
+    #[test]
+    fn state_dicts_load_into_a_fresh_model() {
+        let mlp = &models()[0];
+        let state = mlp.state_dict();
+        assert_eq!(state.len(), mlp.parameters().len());
+        assert_eq!(state["layers.0.neurons.1.w2"], mlp.layers[0].neurons[1].weights[2].get_data());
+        assert_eq!(state["layers.2.neurons.0.b"], mlp.layers[2].neurons[0].bias.as_ref().unwrap().get_data());
+
+        let fresh: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(INPUTS.len()).layers(&[4, 3, 2])
+            .activation(Activation::Identity).output_activation(Activation::Tanh).seed(99).build()
+            .expect("Invalid model");
+        assert_ne!(fresh.state_dict(), state);
+        assert_eq!(fresh.load_state_dict(&state, true), Ok(StateDictKeys::default()));
+        assert_eq!(fresh.state_dict(), state);
+        assert_eq!(fresh.predict(&INPUTS), mlp.predict(&INPUTS));
+    }
+
+    #[test]
+    fn strict_loads_reject_mismatched_names_and_change_nothing() {
+        let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(3).layers(&[2, 1]).seed(1).build()
+            .expect("Invalid model");
+        let before = mlp.state_dict();
+        let mut state: HashMap<String, f64> = before.keys().map(|name| (name.clone(), 0.5)).collect();
+        state.remove("layers.0.neurons.1.w2");
+        state.remove("layers.1.neurons.0.b");
+        state.insert("layers.2.neurons.0.w0".to_string(), 1.0);
+        state.insert("layers.0.neurons.0.w9".to_string(), 1.0);
+
+        let missing = vec!["layers.0.neurons.1.w2".to_string(), "layers.1.neurons.0.b".to_string()];
+        let unexpected = vec!["layers.0.neurons.0.w9".to_string(), "layers.2.neurons.0.w0".to_string()];
+        assert_eq!(mlp.load_state_dict(&state, true),
+                   Err(NnError::StateDictMismatch { missing: missing.clone(), unexpected: unexpected.clone() }));
+        assert_eq!(mlp.state_dict(), before);
+
+        // Only missing, or only unexpected, names are enough to fail
+        let mut only_unexpected: HashMap<String, f64> = before.clone();
+        only_unexpected.insert("extra".to_string(), 1.0);
+        assert_eq!(mlp.load_state_dict(&only_unexpected, true),
+                   Err(NnError::StateDictMismatch { missing: vec![], unexpected: vec!["extra".to_string()] }));
+        let error = mlp.load_state_dict(&HashMap::new(), true).err();
+        assert!(matches!(error, Some(NnError::StateDictMismatch { missing, .. }) if missing.len() == before.len()));
+        assert_eq!(mlp.state_dict(), before);
+
+        // Without strict, the matching values load and the mismatches come back
+        assert_eq!(mlp.load_state_dict(&state, false), Ok(StateDictKeys { missing, unexpected }));
+        let after = mlp.state_dict();
+        for (name, value) in &after {
+            let expected = if state.contains_key(name) { 0.5 } else { before[name] };
+            assert_eq!(*value, expected, "{}", name);
+        }
+    }
+
+    fn scalars(values: &[f64]) -> Vec<Scalar<f64>> {
+        values.iter().map(|x| Scalar::new(*x)).collect()
+    }
+
+    fn data(scalars: &[Scalar<f64>]) -> Vec<f64> {
+        scalars.iter().map(|s| s.get_data()).collect()
+    }
+
+    #[test]
+    fn sequential_rejects_stages_of_different_widths() {
+        let error = Sequential::<f64>::new(vec![
+            Box::new(Layer::new(3, 4).expect("Invalid layer")),
+            Box::new(LayerNorm::new(5).expect("Invalid layer norm")),
+        ]).err();
+        assert_eq!(error, Some(NnError::StageMismatch { stage: 1, expected: 5, actual: 4 }));
+
+        // Modules that take any number of inputs pass the width on
+        let error = Sequential::<f64>::new(vec![
+            Box::new(Layer::new(3, 4).expect("Invalid layer")),
+            Box::new(ActivationLayer::new(Activation::Relu)),
+            Box::new(Softmax::new()),
+            Box::new(Layer::new(5, 1).expect("Invalid layer")),
+        ]).err();
+        assert_eq!(error, Some(NnError::StageMismatch { stage: 3, expected: 5, actual: 4 }));
+
+        let model = Sequential::<f64>::new(vec![
+            Box::new(ActivationLayer::new(Activation::Tanh)),
+            Box::new(Layer::new(3, 2).expect("Invalid layer")),
+        ]).expect("Invalid model");
+        assert_eq!(model.forward(&scalars(&INPUTS)).map(|outputs| outputs.len()), Ok(2));
+        assert_eq!(model.forward(&scalars(&[1.0, 2.0])).err(), Some(NnError::ShapeMismatch { expected: 3, actual: 2 }));
+    }
+
+    #[test]
+    fn softmax_outputs_probabilities_and_their_gradients() {
+        let inputs = scalars(&[1.0, -2.0, 0.5, 3.0]);
+        let outputs = Module::<f64>::forward(&Softmax::new(), &inputs).unwrap();
+        let probabilities = data(&outputs);
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        let exps: Vec<f64> = [1.0f64, -2.0, 0.5, 3.0].iter().map(|x| x.exp()).collect();
+        for (p, e) in probabilities.iter().zip(&exps) {
+            assert!((p - e / exps.iter().sum::<f64>()).abs() < 1e-12);
+        }
+
+        // d softmax_0 / d x_j = p_0 * (1 - p_0) for j = 0, and -p_0 * p_j otherwise
+        outputs[0].backward();
+        for (j, input) in inputs.iter().enumerate() {
+            let expected = probabilities[0] * (if j == 0 { 1.0 } else { 0.0 } - probabilities[j]);
+            assert!((input.get_grad() - expected).abs() < 1e-12, "{}: {} != {}", j, input.get_grad(), expected);
+        }
+
+        // Large inputs don't overflow
+        let large = Module::<f64>::forward(&Softmax::new(), &scalars(&[1000.0, 1000.0])).unwrap();
+        assert_eq!(data(&large), [0.5, 0.5]);
+    }
+
+    #[test]
+    fn dropout_zeroes_and_rescales_in_training_only() {
+        let inputs = scalars(&[1.0; 1000]);
+        let mut dropout = Dropout::new_with_rng(0.25, StdRng::seed_from_u64(5)).expect("Invalid dropout");
+        let outputs = Module::<f64>::forward(&dropout, &inputs).unwrap();
+        let num_dropped = outputs.iter().filter(|o| o.get_data() == 0.0).count();
+        assert!((200..300).contains(&num_dropped), "{} dropped", num_dropped);
+        assert!(outputs.iter().all(|o| o.get_data() == 0.0 || o.get_data() == 1.0 / 0.75));
+        // Dropped inputs get no gradient, kept ones get the scale
+        outputs.iter().for_each(|o| o.backward());
+        assert!(inputs.iter().zip(&outputs).all(|(i, o)| i.get_grad() == o.get_data()));
+
+        // The same seed drops the same inputs
+        let again = Dropout::new_with_rng(0.25, StdRng::seed_from_u64(5)).expect("Invalid dropout");
+        assert_eq!(data(&Module::<f64>::forward(&again, &inputs).unwrap()), data(&outputs));
+
+        Module::<f64>::eval(&mut dropout);
+        let outputs = Module::<f64>::forward(&dropout, &inputs).unwrap();
+        assert!(inputs.iter().zip(&outputs).all(|(i, o)| i.id() == o.id()));
+
+        assert!(Dropout::new(1.0).is_err() && Dropout::new(-0.1).is_err() && Dropout::new(f64::NAN).is_err());
+    }
+
+    #[test]
+    fn layer_norm_gives_zero_mean_and_unit_variance() {
+        let moments = |outputs: &[f64]| {
+            let mean = outputs.iter().sum::<f64>() / outputs.len() as f64;
+            (mean, outputs.iter().map(|o| (o - mean).powi(2)).sum::<f64>() / outputs.len() as f64)
+        };
+        let inputs = scalars(&[1.0, 2.0, 3.0, 4.0, 10.0]);
+        let norm: LayerNorm<f64> = LayerNorm::new(5).expect("Invalid layer norm");
+        let (mean, variance) = moments(&data(&norm.forward(&inputs).unwrap()));
+        assert!(mean.abs() < 1e-12, "{}", mean);
+        assert!((variance - 1.0).abs() < 1e-5, "{}", variance);
+
+        // Then the learned scale and shift
+        norm.gamma.iter().for_each(|g| g.set_data(2.0));
+        norm.beta.iter().for_each(|b| b.set_data(1.0));
+        let (mean, variance) = moments(&data(&norm.forward(&inputs).unwrap()));
+        assert!((mean - 1.0).abs() < 1e-12, "{}", mean);
+        assert!((variance - 4.0).abs() < 1e-4, "{}", variance);
+
+        assert_eq!(norm.forward(&inputs[..4]).err(), Some(NnError::ShapeMismatch { expected: 5, actual: 4 }));
+    }
+
+    fn row(name: &str, kind: &str, sizes: (usize, usize), activation: Option<Activation>, num_parameters: usize)
+        -> LayerSummary {
+        LayerSummary {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            num_inputs: Some(sizes.0),
+            num_outputs: Some(sizes.1),
+            activation,
+            num_parameters,
+        }
+    }
+
+    #[test]
+    fn sequential_summary() {
+        let model: Sequential<f64> = Sequential::new(vec![
+            Box::new(Layer::new_with_activation(3, 8, Activation::Identity).unwrap()),
+            Box::new(ActivationLayer::new(Activation::Relu)),
+            Box::new(Dropout::new(0.25).unwrap()),
+            Box::new(LayerNorm::new(8).unwrap()),
+            Box::new(Layer::new_with_activation(8, 2, Activation::Identity).unwrap()),
+            Box::new(Softmax::new()),
+        ]).unwrap();
+        let summary = model.summary();
+        assert_eq!(summary, ModelSummary {
+            layers: vec![
+                row("0", "Layer", (3, 8), Some(Activation::Identity), 32),
+                row("1", "ActivationLayer", (8, 8), Some(Activation::Relu), 0),
+                row("2", "Dropout", (8, 8), None, 0),
+                row("3", "LayerNorm", (8, 8), None, 16),
+                row("4", "Layer", (8, 2), Some(Activation::Identity), 18),
+                row("5", "Softmax", (2, 2), None, 0),
+            ],
+            num_parameters: 66,
+        });
+        assert!(summary.to_string().ends_with("Trainable parameters: 66"));
+    }
+
+    #[test]
+    fn mlp_summary() {
+        let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::builder(3).layers(&[4, 4, 1])
+            .output_activation(Activation::Identity).build().unwrap();
+        assert_eq!(mlp.summary(), ModelSummary {
+            layers: vec![
+                row("layers.0", "Layer", (3, 4), Some(Activation::Tanh), 16),
+                row("layers.1", "Layer", (4, 4), Some(Activation::Tanh), 20),
+                row("layers.2", "Layer", (4, 1), Some(Activation::Identity), 5),
+            ],
+            num_parameters: 41,
+        });
+    }
+
+    #[test]
+    fn forward_var_matches_forward() {
+        for mlp in models() {
+            let outputs = mlp.forward(&INPUTS).expect("Wrong number of inputs");
+            outputs.iter().fold(Scalar::new(0.0), |sum, output| &sum + output).backward();
+
+            let graph = Graph::new();
+            let parameters: Vec<Var<f64>> = mlp.parameters().iter().map(|p| graph.var(p.get_data())).collect();
+            let inputs: Vec<Var<f64>> = INPUTS.iter().map(|x| graph.var(*x)).collect();
+            let var_outputs = mlp.architecture().forward_var(&parameters, &inputs).expect("Wrong number of inputs");
+            var_outputs.iter().fold(graph.var(0.0), |sum, output| &sum + output).backward();
+
+            let architecture = mlp.architecture();
+            let data: Vec<f64> = outputs.iter().map(|o| o.get_data()).collect();
+            let var_data: Vec<f64> = var_outputs.iter().map(|o| o.get_data()).collect();
+            assert_eq!(data, var_data, "{:?}", architecture);
+            let grads: Vec<f64> = mlp.parameters().iter().map(|p| p.get_grad()).collect();
+            let var_grads: Vec<f64> = parameters.iter().map(|p| p.get_grad()).collect();
+            assert_eq!(grads, var_grads, "{:?}", architecture);
+        }
+    }
+
+    #[test]
+    fn forward_var_checks_inputs_and_parameters() {
+        let mlp: MultiLayerPerceptron<f64> = MultiLayerPerceptron::new(3, &[4, 1]).expect("Invalid model");
+        let architecture = mlp.architecture();
+        let graph = Graph::new();
+        let parameters: Vec<Var<f64>> = mlp.parameters().iter().map(|p| graph.var(p.get_data())).collect();
+        let inputs: Vec<Var<f64>> = INPUTS.iter().map(|x| graph.var(*x)).collect();
+        assert_eq!(architecture.num_parameters(), 21);
+        assert!(architecture.forward_var(&parameters, &inputs).is_ok());
+
+        assert_eq!(architecture.forward_var(&parameters, &inputs[..2]).err(),
+                   Some(NnError::ShapeMismatch { expected: 3, actual: 2 }));
+        assert_eq!(architecture.forward_var(&parameters[..20], &inputs).err(),
+                   Some(NnError::ShapeMismatch { expected: 21, actual: 20 }));
+        let extra = [parameters.clone(), vec![graph.var(1.0)]].concat();
+        assert_eq!(architecture.forward_var(&extra, &inputs).err(),
+                   Some(NnError::ShapeMismatch { expected: 21, actual: 22 }));
+
+        let no_layers = Architecture {
+            num_inputs: 3,
+            layer_num_outputs: vec![],
+            layer_activations: vec![],
+            layer_biases: vec![],
+        };
+        assert_eq!(no_layers.forward_var(&[], &inputs).err(), Some(NnError::EmptyModel));
+        let zero_width = Architecture { layer_num_outputs: vec![0, 1], ..architecture.clone() };
+        assert!(matches!(zero_width.forward_var(&parameters, &inputs), Err(NnError::InvalidConfig(_))));
+        let missing_activation = Architecture { layer_activations: vec![Activation::Tanh], ..architecture };
+        assert!(matches!(missing_activation.forward_var(&parameters, &inputs), Err(NnError::InvalidConfig(_))));
     }
 }
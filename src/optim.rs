@@ -0,0 +1,107 @@
+use std::fmt::Display;
+
+use num_traits::Float;
+
+use crate::nn::NnError;
+use crate::Scalar;
+
+/// The Adam optimizer. It keeps a running mean (`m`) and uncentred variance (`v`) of each parameter's gradient, and
+/// steps each parameter by its bias-corrected mean over the square root of its variance.
+pub struct Adam<T> {
+    parameters: Vec<Scalar<T>>,
+    pub learning_rate: T,
+    pub beta1: T,
+    pub beta2: T,
+    pub epsilon: T,
+    m: Vec<T>,
+    v: Vec<T>,
+    num_steps: u64,
+}
+
+/// Everything `Adam` needs to carry on from where it stopped, apart from its parameters. `m` and `v` are in the order
+/// of the parameters the optimizer was created with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdamState<T> {
+    pub learning_rate: T,
+    pub beta1: T,
+    pub beta2: T,
+    pub epsilon: T,
+    pub num_steps: u64,
+    pub m: Vec<T>,
+    pub v: Vec<T>,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Adam<T> {
+    /// Optimize `parameters`, e.g. a model's `parameters()`, with the usual defaults for the other hyperparameters
+    pub fn new(parameters: Vec<Scalar<T>>, learning_rate: T) -> Self {
+        let num_parameters = parameters.len();
+        Adam {
+            parameters,
+            learning_rate,
+            beta1: T::from(0.9).unwrap(),
+            beta2: T::from(0.999).unwrap(),
+            epsilon: T::from(1e-8).unwrap(),
+            m: vec![T::zero(); num_parameters],
+            v: vec![T::zero(); num_parameters],
+            num_steps: 0,
+        }
+    }
+
+    pub fn parameters(&self) -> &[Scalar<T>] {
+        &self.parameters
+    }
+
+    pub fn num_steps(&self) -> u64 {
+        self.num_steps
+    }
+
+    pub fn zero_grad(&self) {
+        self.parameters.iter().for_each(|p| p.zero_grad());
+    }
+
+    /// Update the parameters from their current gradients
+    pub fn step(&mut self) {
+        self.num_steps += 1;
+        // Past i32::MAX steps, both corrections are one anyway
+        let t = i32::try_from(self.num_steps).unwrap_or(i32::MAX);
+        let m_correction = T::one() - self.beta1.powi(t);
+        let v_correction = T::one() - self.beta2.powi(t);
+        for (i, p) in self.parameters.iter().enumerate() {
+            let grad = p.get_grad();
+            self.m[i] = self.beta1 * self.m[i] + (T::one() - self.beta1) * grad;
+            self.v[i] = self.beta2 * self.v[i] + (T::one() - self.beta2) * grad * grad;
+            let m_hat = self.m[i] / m_correction;
+            let v_hat = self.v[i] / v_correction;
+            p.add_to_data(-self.learning_rate * m_hat / (v_hat.sqrt() + self.epsilon));
+        }
+    }
+
+    pub fn state(&self) -> AdamState<T> {
+        AdamState {
+            learning_rate: self.learning_rate,
+            beta1: self.beta1,
+            beta2: self.beta2,
+            epsilon: self.epsilon,
+            num_steps: self.num_steps,
+            m: self.m.clone(),
+            v: self.v.clone(),
+        }
+    }
+
+    /// Carry on from `state`, which must be for the same number of parameters
+    pub fn load_state(&mut self, state: &AdamState<T>) -> Result<(), NnError> {
+        for moments in [&state.m, &state.v] {
+            if moments.len() != self.parameters.len() {
+                Err(NnError::ShapeMismatch { expected: self.parameters.len(), actual: moments.len() })?
+            }
+        }
+        self.learning_rate = state.learning_rate;
+        self.beta1 = state.beta1;
+        self.beta2 = state.beta2;
+        self.epsilon = state.epsilon;
+        self.num_steps = state.num_steps;
+        self.m = state.m.clone();
+        self.v = state.v.clone();
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::Display;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+
+use num_traits::Float;
+
+use crate::{Graph, Scalar, Var};
+
+/// Parameter values and gradients that can be shared between threads. `Scalar` graphs can't cross threads, so each
+/// worker builds its own arena `Graph`, binds the parameters into it with `bind`, and hands its gradients back with
+/// `accumulate_grads`.
+pub struct SharedParameters<T> {
+    data: RwLock<Vec<T>>,
+    grads: Mutex<Vec<T>>,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + Send + Sync + 'static> SharedParameters<T> {
+    pub fn new(data: Vec<T>) -> Self {
+        let grads = vec![T::zero(); data.len()];
+        SharedParameters {
+            data: RwLock::new(data),
+            grads: Mutex::new(grads),
+        }
+    }
+
+    pub fn from_scalars(parameters: &[Scalar<T>]) -> Self {
+        Self::new(parameters.iter().map(|p| p.get_data()).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get_data(&self) -> Vec<T> {
+        self.data.read().unwrap().clone()
+    }
+
+    pub fn get_grads(&self) -> Vec<T> {
+        self.grads.lock().unwrap().clone()
+    }
+
+    pub fn zero_grad(&self) {
+        self.grads.lock().unwrap().fill(T::zero());
+    }
+
+    /// Create a leaf in `graph` for each parameter, holding its current value
+    pub fn bind<'g>(&self, graph: &'g Graph<T>) -> Vec<Var<'g, T>> {
+        self.data.read().unwrap().iter().map(|d| graph.var(*d)).collect()
+    }
+
+    /// Add the gradients of variables created by `bind` to the shared gradients. Panics if there isn't one variable
+    /// per parameter.
+    pub fn accumulate_grads(&self, parameters: &[Var<T>]) {
+        let mut grads = self.grads.lock().unwrap();
+        assert_eq!(parameters.len(), grads.len(), "Expected a gradient for each of the {} parameters", grads.len());
+        for (grad, parameter) in grads.iter_mut().zip(parameters) {
+            *grad += parameter.get_grad();
+        }
+    }
+
+    /// Move every parameter against its gradient
+    pub fn step(&self, learning_rate: T) {
+        let grads = self.grads.lock().unwrap();
+        for (data, grad) in self.data.write().unwrap().iter_mut().zip(grads.iter()) {
+            *data = *data - *grad * learning_rate;
+        }
+    }
+
+    /// Copy the current values into `Scalar` parameters, e.g. those of the model these were created from. Panics if
+    /// there isn't one `Scalar` per parameter.
+    pub fn copy_to_scalars(&self, parameters: &[Scalar<T>]) {
+        let data = self.data.read().unwrap();
+        assert_eq!(parameters.len(), data.len(), "Expected a Scalar for each of the {} parameters", data.len());
+        for (data, parameter) in data.iter().zip(parameters) {
+            parameter.add_to_data(*data - parameter.get_data());
+        }
+    }
+
+    /// Run `f` on every sample, split into contiguous chunks across `num_threads` threads. Each thread has its own
+    /// graph, with the parameters bound into it; the results come back in the order of `batch`.
+    pub fn map_batch<S, R, F>(&self, batch: &[S], num_threads: usize, f: F) -> Vec<R>
+    where
+        S: Sync,
+        R: Send,
+        F: for<'g> Fn(&'g Graph<T>, &[Var<'g, T>], &S) -> R + Sync,
+    {
+        let chunk_size = batch.len().div_ceil(num_threads.max(1)).max(1);
+        thread::scope(|scope| {
+            let workers: Vec<_> = batch.chunks(chunk_size).map(|chunk| {
+                let f = &f;
+                scope.spawn(move || {
+                    let graph = Graph::new();
+                    let parameters = self.bind(&graph);
+                    let num_parameters = graph.len();
+                    chunk.iter().map(|sample| {
+                        let result = f(&graph, &parameters, sample);
+                        graph.truncate(num_parameters);
+                        result
+                    }).collect::<Vec<R>>()
+                })
+            }).collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        })
+    }
+
+    /// Backpropagate the loss built by `loss` for every sample, split across `num_threads` threads, and add the
+    /// gradients to the shared gradients. Returns the total loss. Each sample's loss and gradients are added in the
+    /// order of `batch`, so the result is the same for any number of threads.
+    pub fn backward_batch<S, F>(&self, batch: &[S], num_threads: usize, loss: F) -> T
+    where
+        S: Sync,
+        F: for<'g> Fn(&'g Graph<T>, &[Var<'g, T>], &S) -> Var<'g, T> + Sync,
+    {
+        let sample_results: Vec<(T, Vec<T>)> = self.map_batch(batch, num_threads, |graph, parameters, sample| {
+            // The parameters are leaves, so their gradients would otherwise accumulate across the thread's samples
+            parameters.iter().for_each(|p| p.zero_grad());
+            let sample_loss = loss(graph, parameters, sample);
+            sample_loss.backward();
+            (sample_loss.get_data(), parameters.iter().map(|p| p.get_grad()).collect())
+        });
+
+        let mut grads = self.grads.lock().unwrap();
+        let mut total = T::zero();
+        for (sample_loss, sample_grads) in sample_results {
+            total += sample_loss;
+            for (grad, sample_grad) in grads.iter_mut().zip(sample_grads) {
+                *grad += sample_grad;
+            }
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "Expected a gradient for each of the 3 parameters")]
+    fn accumulating_too_few_gradients_panics() {
+        let shared = SharedParameters::new(vec![1.0, 2.0, 3.0]);
+        let graph = Graph::new();
+        let parameters = shared.bind(&graph);
+        shared.accumulate_grads(&parameters[..2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected a Scalar for each of the 3 parameters")]
+    fn copying_to_too_many_scalars_panics() {
+        let shared = SharedParameters::new(vec![1.0, 2.0, 3.0]);
+        shared.copy_to_scalars(&[Scalar::new(0.0), Scalar::new(0.0), Scalar::new(0.0), Scalar::new(0.0)]);
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use num_traits::Float;
+
+use crate::Scalar;
+
+// Each level of parentheses, function call or unary minus recurses through the parser, so deeper input is an error
+// rather than a stack overflow
+const MAX_DEPTH: usize = 256;
+
+/// What went wrong while parsing, and where: `position` counts characters from the start of the source
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at position {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Symbol(char),
+    End,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::Symbol(c) => write!(f, "'{}'", c),
+            Token::End => write!(f, "end of input"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            // Exponent, as in 1e-3
+            if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+                let mut j = i + 1;
+                if j < chars.len() && (chars[j] == '+' || chars[j] == '-') {
+                    j += 1;
+                }
+                if j < chars.len() && chars[j].is_ascii_digit() {
+                    i = j;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text.parse::<f64>().map_err(|_| ParseError {
+                position: start,
+                message: format!("Invalid number {}", text),
+            })?;
+            tokens.push((start, Token::Number(number)));
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((start, Token::Identifier(chars[start..i].iter().collect())));
+        } else if "+-*/^()".contains(c) {
+            tokens.push((start, Token::Symbol(c)));
+            i += 1;
+        } else {
+            Err(ParseError { position: start, message: format!("Unexpected character '{}'", c) })?
+        }
+    }
+    tokens.push((chars.len(), Token::End));
+    Ok(tokens)
+}
+
+/// Build the `Scalar` graph for an expression such as `tanh(x1*w1 + x2*w2 + b)`. Variables are looked up in
+/// `variables`, so gradients end up in the caller's `Scalar`s; any of them without a label is labelled with its name.
+///
+/// Supports `+`, `-`, `*`, `/`, unary minus, `^` with a numeric exponent, parentheses, numeric literals, and the
+/// functions `exp`, `tanh` and `relu`. The usual precedence applies, with `^` binding tightest and to the right, so
+/// `-x^2` is `-(x^2)` and `x^2^3` is `x^8`. Nesting is limited to 256 levels.
+pub fn parse<T: Float + Copy + Display + std::ops::AddAssign + 'static>(
+    source: &str, variables: &HashMap<String, Scalar<T>>) -> Result<Scalar<T>, ParseError> {
+    let mut parser = Parser { tokens: tokenize(source)?, next: 0, depth: 0, variables };
+    let scalar = parser.expression()?;
+    match parser.peek() {
+        Token::End => Ok(scalar),
+        token => Err(parser.error(format!("Unexpected {}", token))),
+    }
+}
+
+struct Parser<'a, T> {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    depth: usize,
+    variables: &'a HashMap<String, Scalar<T>>,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Parser<'_, T> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].1
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].1.clone();
+        if token != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    // An error at the next token
+    fn error(&self, message: String) -> ParseError {
+        ParseError { position: self.tokens[self.next].0, message }
+    }
+
+    // Parse one more level of nesting with `parse`, just after the '(', '-' or '^' that opens it
+    fn nested<R>(&mut self, parse: impl FnOnce(&mut Self) -> Result<R, ParseError>) -> Result<R, ParseError> {
+        if self.depth == MAX_DEPTH {
+            Err(ParseError {
+                position: self.tokens[self.next - 1].0,
+                message: format!("Expressions can't nest more than {} levels deep", MAX_DEPTH),
+            })?
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Token::Symbol(c) if *c == symbol => {
+                self.advance();
+                Ok(())
+            }
+            token => Err(self.error(format!("Expected '{}', not {}", symbol, token))),
+        }
+    }
+
+    // expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<Scalar<T>, ParseError> {
+        let mut lhs = self.term()?;
+        while let Token::Symbol(op @ ('+' | '-')) = *self.peek() {
+            self.advance();
+            let rhs = self.term()?;
+            lhs = if op == '+' { &lhs + &rhs } else { &lhs - &rhs };
+        }
+        Ok(lhs)
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Scalar<T>, ParseError> {
+        let mut lhs = self.unary()?;
+        while let Token::Symbol(op @ ('*' | '/')) = *self.peek() {
+            self.advance();
+            let rhs = self.unary()?;
+            lhs = if op == '*' { &lhs * &rhs } else { &lhs / &rhs };
+        }
+        Ok(lhs)
+    }
+
+    // unary := '-' unary | power
+    fn unary(&mut self) -> Result<Scalar<T>, ParseError> {
+        if *self.peek() == Token::Symbol('-') {
+            self.advance();
+            return Ok(-&self.nested(Self::unary)?);
+        }
+        self.power()
+    }
+
+    // power := primary ('^' exponent)?
+    fn power(&mut self) -> Result<Scalar<T>, ParseError> {
+        let base = self.primary()?;
+        if *self.peek() != Token::Symbol('^') {
+            return Ok(base);
+        }
+        self.advance();
+        Ok(base.pow(T::from(self.exponent()?).unwrap()))
+    }
+
+    // exponent := '-'? number ('^' exponent)?
+    fn exponent(&mut self) -> Result<f64, ParseError> {
+        let sign = if *self.peek() == Token::Symbol('-') {
+            self.advance();
+            -1.0
+        } else {
+            1.0
+        };
+        let n = match *self.peek() {
+            Token::Number(n) => n,
+            ref token => Err(self.error(format!("Expected a numeric exponent, not {}", token)))?,
+        };
+        self.advance();
+        if *self.peek() != Token::Symbol('^') {
+            return Ok(sign * n);
+        }
+        self.advance();
+        Ok(sign * n.powf(self.nested(Self::exponent)?))
+    }
+
+    // primary := number | variable | function '(' expression ')' | '(' expression ')'
+    fn primary(&mut self) -> Result<Scalar<T>, ParseError> {
+        let position = self.tokens[self.next].0;
+        match self.advance() {
+            Token::Number(n) => Ok(Scalar::constant(T::from(n).unwrap())),
+            Token::Symbol('(') => {
+                let scalar = self.nested(Self::expression)?;
+                self.expect(')')?;
+                Ok(scalar)
+            }
+            Token::Identifier(name) if *self.peek() == Token::Symbol('(') => {
+                self.advance();
+                let argument = self.nested(Self::expression)?;
+                self.expect(')')?;
+                match name.as_str() {
+                    "exp" => Ok(argument.exp()),
+                    "tanh" => Ok(argument.tanh()),
+                    "relu" => Ok(argument.relu()),
+                    _ => Err(ParseError { position, message: format!("Unknown function {}", name) }),
+                }
+            }
+            Token::Identifier(name) => {
+                let variable = self.variables.get(&name).ok_or(ParseError {
+                    position,
+                    message: format!("Unknown variable {}", name),
+                })?;
+                if variable.get_label().is_empty() {
+                    variable.set_label(&name);
+                }
+                Ok(variable.clone())
+            }
+            token => Err(ParseError { position, message: format!("Unexpected {}", token) }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, Scalar<f64>> {
+        HashMap::from([("x".to_string(), Scalar::new(2.0)), ("y".to_string(), Scalar::new(3.0))])
+    }
+
+    fn value(source: &str) -> f64 {
+        parse(source, &variables()).unwrap().get_data()
+    }
+
+    fn error(source: &str) -> ParseError {
+        parse(source, &variables()).err().unwrap()
+    }
+
+    #[test]
+    fn operators_follow_precedence_and_associativity() {
+        assert_eq!(value("1 + 2 * 3"), 7.0);
+        assert_eq!(value("2 * 3 ^ 2"), 18.0);
+        assert_eq!(value("(1 + 2) * 3"), 9.0);
+        assert_eq!(value("8 - 4 - 2"), 2.0);
+        assert_eq!(value("8 / 4 / 2"), 1.0);
+        assert_eq!(value("x * y - y / x"), 4.5);
+    }
+
+    #[test]
+    fn unary_minus_binds_looser_than_powers() {
+        assert_eq!(value("-x^2"), -4.0);
+        assert_eq!(value("-x^2"), value("-(x^2)"));
+        assert_eq!(value("(-x)^2"), 4.0);
+        assert_eq!(value("--x"), 2.0);
+        assert_eq!(value("y - -x"), 5.0);
+    }
+
+    #[test]
+    fn exponents_are_numbers_and_chain_to_the_right() {
+        assert_eq!(value("x^-1"), 0.5);
+        assert_eq!(value("x^0.5"), 2.0.sqrt());
+        assert_eq!(value("x^2^3"), 256.0);
+        assert_eq!(value("x^2^-1"), 2.0.sqrt());
+        assert_eq!(value("x^-1^2"), 0.5);
+        assert_eq!(error("x^y"), ParseError { position: 2, message: "Expected a numeric exponent, not y".into() });
+        assert_eq!(error("x^(2)"), ParseError { position: 2, message: "Expected a numeric exponent, not '('".into() });
+        assert_eq!(error("x^2^"), ParseError {
+            position: 4,
+            message: "Expected a numeric exponent, not end of input".into(),
+        });
+    }
+
+    #[test]
+    fn numbers_can_have_exponents() {
+        assert_eq!(value("1e-3"), 0.001);
+        assert_eq!(value("2.5E2"), 250.0);
+        assert_eq!(value("1e+1 * x"), 20.0);
+        assert_eq!(value(".5"), 0.5);
+        assert_eq!(error("1.2.3"), ParseError { position: 0, message: "Invalid number 1.2.3".into() });
+    }
+
+    #[test]
+    fn functions_apply_to_their_argument() {
+        assert_eq!(value("exp(0)"), 1.0);
+        assert_eq!(value("tanh(x - 2)"), 0.0);
+        assert_eq!(value("relu(-x) + relu(y)"), 3.0);
+        assert_eq!(value("exp(tanh(0) * x)"), 1.0);
+    }
+
+    #[test]
+    fn gradients_reach_the_variables() {
+        let variables = variables();
+        let scalar = parse("x * y + x^2", &variables).unwrap();
+        scalar.backward();
+        assert_eq!(variables["x"].get_grad(), 7.0);
+        assert_eq!(variables["y"].get_grad(), 2.0);
+        assert_eq!(variables["x"].get_label(), "x");
+    }
+
+    #[test]
+    fn errors_give_their_position() {
+        let cases = [
+            ("1 +", 3, "Unexpected end of input"),
+            ("", 0, "Unexpected end of input"),
+            ("1 $ 2", 2, "Unexpected character '$'"),
+            ("x + foo(1)", 4, "Unknown function foo"),
+            ("x * z", 4, "Unknown variable z"),
+            ("(x + 1", 6, "Expected ')', not end of input"),
+            ("x y", 2, "Unexpected y"),
+            ("x + )", 4, "Unexpected ')'"),
+        ];
+        for (source, position, message) in cases {
+            assert_eq!(error(source), ParseError { position, message: message.to_string() }, "{}", source);
+        }
+        assert_eq!(error("1 $ 2").to_string(), "at position 2: Unexpected character '$'");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth: usize| format!("{}x{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(value(&nested(MAX_DEPTH)), 2.0);
+        let message = format!("Expressions can't nest more than {} levels deep", MAX_DEPTH);
+        assert_eq!(error(&nested(100_000)), ParseError { position: MAX_DEPTH, message: message.clone() });
+        assert_eq!(error(&format!("{}x", "-".repeat(100_000))), ParseError { position: MAX_DEPTH, message });
+        assert_eq!(error(&format!("{}x", "exp(".repeat(100_000))).position, 4 * MAX_DEPTH + 3);
+        assert_eq!(error(&format!("x{}", "^1".repeat(100_000))).position, 2 * (MAX_DEPTH + 1) + 1);
+    }
+}
@@ -0,0 +1,291 @@
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use num_traits::Float;
+use serde_json::{json, Map, Value};
+
+use crate::nn::{Layer, Neuron, NnError};
+use crate::{Activation, MultiLayerPerceptron};
+
+// The safetensors layout: an 8-byte little-endian header length, a JSON header mapping each tensor's name to its
+// dtype, shape and byte range, then the tensors' raw little-endian data. Layer i is stored like a PyTorch
+// `nn.Linear`, as `layers.{i}.weight` with shape [out, in] and `layers.{i}.bias` with shape [out].
+// Activations aren't tensors, so they go in the header's string-only `__metadata__`.
+
+const METADATA: &str = "__metadata__";
+
+fn invalid(message: String) -> NnError {
+    NnError::InvalidFormat(message)
+}
+
+fn dtype_name<T>() -> Result<&'static str, NnError> {
+    match std::mem::size_of::<T>() {
+        4 => Ok("F32"),
+        8 => Ok("F64"),
+        size => Err(invalid(format!("Only f32 and f64 can be stored as safetensors, not {}-byte floats", size))),
+    }
+}
+
+// A tensor read from the header, with its data decoded
+struct Tensor<T> {
+    shape: Vec<usize>,
+    values: Vec<T>,
+}
+
+// The [start, end) byte range of a tensor's data
+fn data_offsets(name: &str, info: &Value) -> Result<(usize, usize), NnError> {
+    info["data_offsets"].as_array()
+        .and_then(|offsets| offsets.iter().map(|o| o.as_u64().and_then(|o| usize::try_from(o).ok())).collect())
+        .filter(|offsets: &Vec<usize>| offsets.len() == 2 && offsets[0] <= offsets[1])
+        .map(|offsets| (offsets[0], offsets[1]))
+        .ok_or_else(|| invalid(format!("Tensor {} has invalid data offsets", name)))
+}
+
+// The tensors' byte ranges must tile the data exactly: in order, without gaps or overlaps, from the start to the end
+fn check_data_offsets(header: &Map<String, Value>, data_length: usize) -> Result<(), NnError> {
+    let mut ranges = header.iter().filter(|(name, _)| *name != METADATA)
+        .map(|(name, info)| Ok((data_offsets(name, info)?, name)))
+        .collect::<Result<Vec<_>, NnError>>()?;
+    ranges.sort();
+    let mut end = 0;
+    for ((start, next_end), name) in ranges {
+        if start != end {
+            Err(invalid(format!("Tensor {} starts at byte {}, but the previous tensor ends at byte {}",
+                                name, start, end)))?
+        }
+        end = next_end;
+    }
+    if end != data_length {
+        Err(invalid(format!("The tensors cover {} bytes, but there are {} bytes of data", end, data_length)))?
+    }
+    Ok(())
+}
+
+fn read_tensor<T: Float>(name: &str, info: &Value, data: &[u8]) -> Result<Tensor<T>, NnError> {
+    let dtype = info["dtype"].as_str().ok_or_else(|| invalid(format!("Tensor {} has no dtype", name)))?;
+    let shape: Vec<usize> = info["shape"].as_array()
+        .and_then(|shape| shape.iter().map(|d| d.as_u64().and_then(|d| usize::try_from(d).ok())).collect())
+        .ok_or_else(|| invalid(format!("Tensor {} has an invalid shape", name)))?;
+    let (start, end) = data_offsets(name, info)?;
+    let bytes = data.get(start..end).ok_or_else(|| invalid(format!("Tensor {} ends past the data", name)))?;
+
+    // f32 tensors load exactly into f64 models. f64 tensors are rounded to the nearest f32 for f32 models.
+    let element_size: usize = match dtype {
+        "F32" => 4,
+        "F64" => 8,
+        _ => Err(invalid(format!("Tensor {} has unsupported dtype {}", name, dtype)))?,
+    };
+    let num_bytes = shape.iter().try_fold(element_size, |size, d| size.checked_mul(*d))
+        .ok_or_else(|| invalid(format!("Tensor {} has shape {:?}, which is too large", name, shape)))?;
+    if bytes.len() != num_bytes {
+        Err(invalid(format!("Tensor {} has shape {:?} but {} bytes of {}", name, shape, bytes.len(), dtype)))?
+    }
+    let values = bytes.chunks_exact(element_size).map(|chunk| {
+        let value = if element_size == 4 {
+            f32::from_le_bytes(chunk.try_into().unwrap()) as f64
+        } else {
+            f64::from_le_bytes(chunk.try_into().unwrap())
+        };
+        // An f64 too large for an f32 model would otherwise become infinite
+        T::from(value).filter(|v| v.is_finite() || !value.is_finite())
+            .ok_or_else(|| invalid(format!("Tensor {} holds {}, which is out of range", name, value)))
+    }).collect::<Result<_, _>>()?;
+    Ok(Tensor { shape, values })
+}
+
+// MLP ------------------------------------------------------------------------
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> MultiLayerPerceptron<T> {
+    pub fn to_safetensors(&self) -> Result<Vec<u8>, NnError> {
+        let dtype = dtype_name::<T>()?;
+        let mut header = Map::new();
+        let mut data: Vec<u8> = Vec::new();
+        for (name, array) in self.weight_arrays()? {
+            let start = data.len();
+            for value in array.data {
+                // Casting an f32 to f64 and back is exact
+                let value = value.to_f64().unwrap();
+                if dtype == "F32" {
+                    data.extend_from_slice(&(value as f32).to_le_bytes());
+                } else {
+                    data.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            header.insert(name, json!({ "dtype": dtype, "shape": array.shape, "data_offsets": [start, data.len()] }));
+        }
+        let activations: Vec<&str> = self.architecture().layer_activations.iter().map(|a| a.name()).collect();
+        header.insert(METADATA.to_string(), json!({ "format": "micro-grad-mlp", "activations": activations.join(",") }));
+
+        // Pad the header with spaces so the data starts 8-byte aligned
+        let mut header = serde_json::to_string(&header).map_err(|e| invalid(e.to_string()))?.into_bytes();
+        header.resize(header.len().next_multiple_of(8), b' ');
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&data);
+        Ok(bytes)
+    }
+
+    /// Build a model from safetensors written by `to_safetensors` or by Python tools. Layers without an
+    /// `activations` entry in the metadata use tanh.
+    pub fn from_safetensors(bytes: &[u8]) -> Result<Self, NnError> {
+        let header_length = bytes.get(..8).map(|b| u64::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid("File is too short for a safetensors header".to_string()))?;
+        let data_start = header_length.checked_add(8).filter(|start| *start <= bytes.len())
+            .ok_or_else(|| invalid(format!("Header length {} is longer than the file", header_length)))?;
+        let header: Map<String, Value> = serde_json::from_slice(&bytes[8..data_start])
+            .map_err(|e| invalid(format!("Invalid header: {}", e)))?;
+        let data = &bytes[data_start..];
+        check_data_offsets(&header, data.len())?;
+
+        let activations: Vec<Activation> = match header.get(METADATA).and_then(|m| m["activations"].as_str()) {
+            Some(names) => names.split(',').map(|name| {
+                Activation::from_name(name).ok_or_else(|| invalid(format!("Unknown activation {}", name)))
+            }).collect::<Result<_, _>>()?,
+            None => Vec::new(),
+        };
+
+        let mut layers = Vec::new();
+        while let Some(info) = header.get(&format!("layers.{}.weight", layers.len())) {
+            let i = layers.len();
+            let weights: Tensor<T> = read_tensor(&format!("layers.{}.weight", i), info, data)?;
+            if weights.shape.len() != 2 {
+                Err(invalid(format!("layers.{}.weight should be [out, in], not {:?}", i, weights.shape)))?
+            }
+            let (num_outputs, num_inputs) = (weights.shape[0], weights.shape[1]);
+            let biases = match header.get(&format!("layers.{}.bias", i)) {
+                Some(info) => {
+                    let biases: Tensor<T> = read_tensor(&format!("layers.{}.bias", i), info, data)?;
+                    if biases.shape != [num_outputs] {
+                        Err(invalid(format!("layers.{}.bias should be [{}], not {:?}", i, num_outputs, biases.shape)))?
+                    }
+                    Some(biases.values)
+                }
+                None => None,
+            };
+            let activation = activations.get(i).copied().unwrap_or(Activation::Tanh);
+            let neurons = (0..num_outputs).map(|j| {
+                let bias = biases.as_ref().map(|b| b[j]);
+                Neuron::from_values(&weights.values[j * num_inputs..(j + 1) * num_inputs], bias, activation)
+            }).collect();
+            layers.push(Layer::from_neurons(neurons));
+        }
+
+        let expected = |name: &str| {
+            let parts: Vec<&str> = name.split('.').collect();
+            parts.len() == 3 && parts[0] == "layers" && parts[1].parse::<usize>().is_ok_and(|i| i < layers.len())
+                && (parts[2] == "weight" || parts[2] == "bias")
+        };
+        if let Some(name) = header.keys().find(|name| *name != METADATA && !expected(name)) {
+            Err(invalid(format!("Unexpected tensor {}", name)))?
+        }
+        MultiLayerPerceptron::from_layers(layers)
+    }
+
+    pub fn save_safetensors<P: AsRef<Path>>(&self, path: P) -> Result<(), NnError> {
+        fs::write(path, self.to_safetensors()?)?;
+        Ok(())
+    }
+
+    pub fn load_safetensors<P: AsRef<Path>>(path: P) -> Result<Self, NnError> {
+        Self::from_safetensors(&fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model<T: Float + Copy + Display + std::ops::AddAssign + 'static>(bias: bool) -> MultiLayerPerceptron<T> {
+        MultiLayerPerceptron::builder(3).layers(&[4, 2]).activation(Activation::Relu)
+            .output_activation(Activation::Sigmoid).bias(bias).seed(5).build().unwrap()
+    }
+
+    fn parameter_values<T>(mlp: &MultiLayerPerceptron<T>) -> Vec<f64>
+    where
+        T: Float + Copy + Display + std::ops::AddAssign + 'static,
+    {
+        mlp.weight_arrays().unwrap().into_iter()
+            .flat_map(|(_, array)| array.data)
+            .map(|v| v.to_f64().unwrap())
+            .collect()
+    }
+
+    // A file with the given header entries, and `data_length` bytes of data
+    fn file(tensors: Value, data_length: usize) -> Vec<u8> {
+        let header = tensors.to_string().into_bytes();
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&header);
+        bytes.resize(bytes.len() + data_length, 0);
+        bytes
+    }
+
+    fn error(bytes: &[u8]) -> String {
+        match MultiLayerPerceptron::<f64>::from_safetensors(bytes) {
+            Err(NnError::InvalidFormat(message)) => message,
+            Err(error) => panic!("Unexpected error {}", error),
+            Ok(_) => panic!("Loaded an invalid file"),
+        }
+    }
+
+    #[test]
+    fn round_trips_keep_weights_and_activations() {
+        for bias in [true, false] {
+            let mlp = model::<f32>(bias);
+            let loaded = MultiLayerPerceptron::<f32>::from_safetensors(&mlp.to_safetensors().unwrap()).unwrap();
+            assert_eq!(loaded.architecture(), mlp.architecture());
+            assert_eq!(parameter_values(&loaded), parameter_values(&mlp));
+
+            // f32 values are exact in an f64 model
+            let widened = MultiLayerPerceptron::<f64>::from_safetensors(&mlp.to_safetensors().unwrap()).unwrap();
+            assert_eq!(parameter_values(&widened), parameter_values(&mlp));
+
+            let mlp = model::<f64>(bias);
+            let loaded = MultiLayerPerceptron::<f64>::from_safetensors(&mlp.to_safetensors().unwrap()).unwrap();
+            assert_eq!(loaded.architecture(), mlp.architecture());
+            assert_eq!(parameter_values(&loaded), parameter_values(&mlp));
+        }
+    }
+
+    #[test]
+    fn data_offsets_must_tile_the_data() {
+        let tensor = |start: usize, end: usize, shape: &[usize]| {
+            json!({ "dtype": "F32", "shape": shape, "data_offsets": [start, end] })
+        };
+        let overlapping = json!({ "layers.0.weight": tensor(0, 8, &[1, 2]), "layers.0.bias": tensor(4, 8, &[1]) });
+        assert_eq!(error(&file(overlapping, 8)),
+                   "Tensor layers.0.bias starts at byte 4, but the previous tensor ends at byte 8");
+        let gap = json!({ "layers.0.weight": tensor(4, 12, &[1, 2]) });
+        assert_eq!(error(&file(gap, 12)),
+                   "Tensor layers.0.weight starts at byte 4, but the previous tensor ends at byte 0");
+        let uncovered = json!({ "layers.0.weight": tensor(0, 8, &[1, 2]) });
+        assert_eq!(error(&file(uncovered, 12)), "The tensors cover 8 bytes, but there are 12 bytes of data");
+        let backwards = json!({ "layers.0.weight": tensor(8, 0, &[1, 2]) });
+        assert_eq!(error(&file(backwards, 8)), "Tensor layers.0.weight has invalid data offsets");
+        let tiled = json!({ "layers.0.bias": tensor(0, 4, &[1]), "layers.0.weight": tensor(4, 12, &[1, 2]) });
+        assert!(MultiLayerPerceptron::<f64>::from_safetensors(&file(tiled, 12)).is_ok());
+    }
+
+    #[test]
+    fn oversized_shapes_are_rejected() {
+        let huge = json!({ "layers.0.weight": { "dtype": "F64", "shape": [u64::MAX, 2], "data_offsets": [0, 16] } });
+        assert_eq!(error(&file(huge, 16)),
+                   format!("Tensor layers.0.weight has shape [{}, 2], which is too large", usize::MAX));
+    }
+
+    #[test]
+    fn f64_values_out_of_range_for_f32_are_rejected() {
+        let mlp = model::<f64>(true);
+        crate::Module::parameters(&mlp)[0].add_to_data(1e300);
+        let bytes = mlp.to_safetensors().unwrap();
+        assert!(matches!(MultiLayerPerceptron::<f32>::from_safetensors(&bytes), Err(NnError::InvalidFormat(_))));
+        assert!(MultiLayerPerceptron::<f64>::from_safetensors(&bytes).is_ok());
+    }
+
+    #[test]
+    fn activations_are_read_by_name() {
+        let header = json!({ "layers.0.weight": { "dtype": "F32", "shape": [1, 1], "data_offsets": [0, 4] },
+                             METADATA: { "activations": "gelu" } });
+        assert_eq!(error(&file(header, 4)), "Unknown activation gelu");
+    }
+}
@@ -1,19 +1,75 @@
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter, Result};
 use std::hash::{Hash, Hasher};
 use std::ops::{Add, Div, Mul, Neg, Sub};
 use std::ptr;
 // use std::iter::Sum;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use num_traits::Float;
 
+// Shared by every thread, so that it also covers the arena graphs of parallel workers
+static DETECT_ANOMALY: AtomicBool = AtomicBool::new(false);
+
+/// Turn anomaly detection on or off for every thread. While it is on, every node's data is checked when the node is
+/// created, leaves included, and every gradient after a node's `back_prop` runs. This applies to `Scalar` graphs and
+/// to arena `Graph`s, including those built by `SharedParameters` and `DataParallelTrainer` workers.
+///
+/// The first NaN or infinity panics, rather than silently spreading through every parameter. The panic message
+/// (also logged as an error) names the offending node's label, op and producers. Use `std::panic::catch_unwind` to
+/// recover from it.
+pub fn set_detect_anomaly(enabled: bool) {
+    DETECT_ANOMALY.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_anomaly_detection_enabled() -> bool {
+    DETECT_ANOMALY.load(Ordering::Relaxed)
+}
+
+// The operation that produced a value
+#[derive(Clone)]
+pub(crate) enum Op<T> {
+    Leaf,
+    // A leaf created for a number, e.g. by add_number
+    Constant,
+    Add,
+    Mul,
+    Pow(T),
+    Exp,
+    Tanh,
+    Relu,
+    // Producers are the weights, then the inputs, then the bias
+    Dot,
+    Custom(Rc<dyn CustomOp<T>>),
+}
+
+impl<T: Display> Display for Op<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Op::Leaf => write!(f, "leaf"),
+            Op::Constant => write!(f, "constant"),
+            Op::Add => write!(f, "+"),
+            Op::Mul => write!(f, "*"),
+            Op::Pow(power) => write!(f, "^{}", power),
+            Op::Exp => write!(f, "exp"),
+            Op::Tanh => write!(f, "tanh"),
+            Op::Relu => write!(f, "relu"),
+            Op::Dot => write!(f, "dot"),
+            Op::Custom(op) => write!(f, "{}", op.name()),
+        }
+    }
+}
+
 struct Value<T> {
     pub data: Rc<RefCell<T>>,
     pub grad: Rc<RefCell<T>>,
     pub label: String,
+    op: Op<T>,
     pub back_prop: Option<Box<dyn Fn()>>,
+    // Called in order with this value's gradient during backward, each returning the (possibly replaced) gradient
+    hooks: Vec<Rc<dyn Fn(T) -> T>>,
     // A list of values used to produce this value. Empty indicates a "leaf" value
     producers: Vec<Rc<RefCell<Value<T>>>>,
 }
@@ -24,7 +80,9 @@ impl<T: Float + Copy + Display> Value<T> {
             data: Rc::new(RefCell::new(data)),
             grad: Rc::new(RefCell::new(T::zero())),
             label: "".to_string(),
+            op: Op::Leaf,
             back_prop: None,
+            hooks: Vec::new(),
             producers: Vec::new(),
         }
     }
@@ -34,7 +92,9 @@ impl<T: Float + Copy + Display> Value<T> {
             data: Rc::new(RefCell::new(data)),
             grad: Rc::new(RefCell::new(T::zero())),
             label: label.to_string(),
+            op: Op::Leaf,
             back_prop: None,
+            hooks: Vec::new(),
             producers: Vec::new(),
         }
     }
@@ -54,21 +114,43 @@ impl<T> Hash for Value<T> {
     }
 }
 
+/// A differentiable operation defined outside the crate. `Scalar::custom` wires it into the graph like the built-in
+/// operations, with any number of inputs.
+pub trait CustomOp<T> {
+    /// Used to label the output node, e.g. `name(x, y)`
+    fn name(&self) -> &str {
+        "custom"
+    }
+
+    fn forward(&self, inputs: &[T]) -> T;
+
+    /// The gradient of each input, given the output's gradient. Must return exactly one value per input. This is
+    /// checked when the op is added to a graph or traced into a tape; a later call that returns a different number
+    /// panics.
+    fn backward(&self, inputs: &[T], output: T, out_grad: T) -> Vec<T>;
+}
+
+// The gradients `op` returns for `inputs`, or an error if it doesn't return one per input
+pub(crate) fn custom_grads<T>(op: &dyn CustomOp<T>, inputs: &[T], output: T, out_grad: T)
+    -> std::result::Result<Vec<T>, String> {
+    let grads = op.backward(inputs, output, out_grad);
+    if grads.len() != inputs.len() {
+        Err(format!("{} returned {} gradients for {} inputs", op.name(), grads.len(), inputs.len()))?
+    }
+    Ok(grads)
+}
+
 pub struct Scalar<T> {
     value: Rc<RefCell<Value<T>>>,
 }
 
 impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
     pub fn new(data: T) -> Self {
-        Self {
-            value: Rc::new(RefCell::new(Value::new(data))),
-        }
+        Self::new_from_op(Rc::new(RefCell::new(Value::new(data))))
     }
 
     pub fn new_with_label(data: T, label: &str) -> Self {
-        Self {
-            value: Rc::new(RefCell::new(Value::new_with_label(data, label))),
-        }
+        Self::new_from_op(Rc::new(RefCell::new(Value::new_with_label(data, label))))
     }
 
     fn new_from_value(value: Rc<RefCell<Value<T>>>) -> Self {
@@ -77,23 +159,66 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
         }
     }
 
+    // Wrap a new leaf or the output of an operation, checking it for anomalies
+    fn new_from_op(value: Rc<RefCell<Value<T>>>) -> Self {
+        let scalar = Self::new_from_value(value);
+        if is_anomaly_detection_enabled() && !scalar.get_data().is_finite() {
+            let problem = if scalar.value.borrow().producers.is_empty() { "created with" } else { "forward produced" };
+            scalar.report_anomaly(&format!("{} {}", problem, scalar.get_data()));
+        }
+        scalar
+    }
+
+    fn report_anomaly(&self, problem: &str) -> ! {
+        let value = self.value.borrow();
+        let producers: Vec<String> = value.producers.iter()
+            .map(|p| Scalar::new_from_value(p.clone()).to_string())
+            .collect();
+        let message = format!("Anomaly detected: {} at '{}' (op: {}), producers: [{}]",
+            problem, value.label, value.op, producers.join(", "));
+        log::error!("{}", message);
+        panic!("{}", message);
+    }
+
+    // Identifies the node this Scalar refers to; clones of a Scalar share an id
+    pub(crate) fn id(&self) -> usize {
+        Rc::as_ptr(&self.value) as usize
+    }
+
+    pub(crate) fn op(&self) -> Op<T> {
+        self.value.borrow().op.clone()
+    }
+
+    pub(crate) fn producers(&self) -> Vec<Scalar<T>> {
+        self.value.borrow().producers.iter().map(|p| Scalar::new_from_value(p.clone())).collect()
+    }
+
     pub fn get_data(&self) -> T {
-        self.value.borrow().data.borrow().clone()
+        *self.value.borrow().data.borrow()
     }
 
     pub fn get_grad(&self) -> T {
-        self.value.borrow().grad.borrow().clone()
+        *self.value.borrow().grad.borrow()
     }
 
     pub fn zero_grad(&self) {
         self.value.borrow_mut().grad.replace(T::zero());
     }
     
+    pub fn set_data(&self, data: T) {
+        self.value.borrow_mut().data.replace(data);
+    }
+
     pub fn add_to_data(&self, diff: T) {
         let new_value = self.get_data() + diff;
         self.value.borrow_mut().data.replace(new_value);
     }
 
+    pub fn add_to_grad(&self, diff: T) {
+        let new_grad = self.get_grad() + diff;
+        self.value.borrow_mut().grad.replace(new_grad);
+    }
+
     pub fn set_label(&self, label: &str) {
         self.value.borrow_mut().label = label.to_owned();
     }
@@ -102,33 +227,110 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
         self.value.borrow_mut().label.clone()
     }
     
+    /// Register a hook that is called with the gradient arriving at this node during each `backward`, once all of it
+    /// has arrived and before it is passed on to the node's producers. The hook returns the gradient to use instead,
+    /// so it can observe (by returning its argument unchanged), clip or reverse the gradient. Gradients from earlier
+    /// passes that a leaf has accumulated aren't passed to the hook again.
+    pub fn register_hook<F: Fn(T) -> T + 'static>(&self, hook: F) {
+        self.value.borrow_mut().hooks.push(Rc::new(hook));
+    }
+
+    pub fn clear_hooks(&self) {
+        self.value.borrow_mut().hooks.clear();
+    }
+
+    /// Backpropagate from this node, adding its gradient with respect to each leaf to that leaf's gradient. The
+    /// gradients of the nodes between this one and the leaves are recomputed from scratch on every call, so calling
+    /// `backward` twice doubles the leaves' gradients but not theirs. Zero the leaves' gradients (e.g. with
+    /// `Module::zero_grad`) between training steps.
     pub fn backward(&self) {
-        // Build a topological ordering of the graph
+        self.backward_with(T::one());
+    }
+
+    /// Backpropagate from this node, seeding its gradient with `seed` rather than one. This computes a
+    /// vector-Jacobian product, or weights this loss relative to others sharing the same parameters.
+    pub fn backward_with(&self, seed: T) {
+        self.propagate(seed, false);
+    }
+
+    /// Like `backward_with`, but `seed` is added to this node's existing gradient instead of replacing it. Calling
+    /// this on several losses in sequence leaves every leaf holding the gradient of their weighted sum.
+    pub fn backward_retain(&self, seed: T) {
+        self.propagate(seed, true);
+    }
+
+    // `retain` keeps the root's existing gradient, as if it were a leaf
+    fn propagate(&self, seed: T, retain: bool) {
+        let topological_ordering = self.topological_order();
+
+        // Interior gradients are recomputed from scratch on every pass, so that a subgraph shared between several
+        // losses doesn't push the same gradient into its leaves twice. Leaf gradients accumulate as usual. Every
+        // gradient starts the pass at zero and has its earlier value added back once its node has run, so hooks and
+        // producers only see the gradient from this pass.
+        let earlier_grads: Vec<T> = topological_ordering.iter().map(|s| {
+            let accumulates = s.value.borrow().producers.is_empty() || (retain && s == self);
+            let grad = if accumulates { s.get_grad() } else { T::zero() };
+            s.zero_grad();
+            grad
+        }).collect();
+
+        self.value.borrow().grad.replace(seed);
+        for (s, earlier_grad) in topological_ordering.iter().zip(earlier_grads).rev() {
+            // Every consumer of s has already run, so its gradient is complete. The hooks are cloned out of the node
+            // first, so that a hook can use the node, e.g. to relabel it.
+            let hooks = s.value.borrow().hooks.clone();
+            let hooked_grad = hooks.iter().fold(s.get_grad(), |grad, hook| hook(grad));
+            s.value.borrow().grad.replace(hooked_grad);
+
+            if let Some(back_prop) = &s.value.borrow().back_prop {
+                back_prop();
+                let grad = *s.value.borrow().grad.borrow();
+                log::debug!("{}'s gradient: {:.4}", s.value.borrow().label, grad);
+            }
+            if is_anomaly_detection_enabled() {
+                let producers = s.value.borrow().producers.clone();
+                if let Some(producer) = producers.iter().find(|p| !p.borrow().grad.borrow().is_finite()) {
+                    let grad = *producer.borrow().grad.borrow();
+                    s.report_anomaly(&format!("backward produced gradient {} for '{}'", grad, producer.borrow().label));
+                }
+            }
+            s.value.borrow().grad.replace(earlier_grad + hooked_grad);
+        }
+    }
+
+    // Every node reachable from this one, ordered so that each node comes after all of its producers
+    pub(crate) fn topological_order(&self) -> Vec<Scalar<T>> {
         let mut topological_ordering = Vec::<Scalar<T>>::new();
-        let mut visited = HashSet::<Scalar<T>>::new();
+        let mut visited = HashSet::<*const RefCell<Value<T>>>::new();
 
         fn visit<T: Float + Copy + Display + std::ops::AddAssign + 'static>(
             topological_ordering: &mut Vec<Scalar<T>>,
-            visited: &mut HashSet<Scalar<T>>,
+            visited: &mut HashSet<*const RefCell<Value<T>>>,
             scalar: &Scalar<T>) {
-            if !visited.contains(scalar) {
-                visited.insert(scalar.clone());
+            if visited.insert(Rc::as_ptr(&scalar.value)) {
                 for child in scalar.value.borrow().producers.clone() {
                     visit(topological_ordering, visited, &Scalar::new_from_value(child));
                 }
                 topological_ordering.push(scalar.clone());
             }
         }
-        visit(&mut topological_ordering, &mut visited, &self);
+        visit(&mut topological_ordering, &mut visited, self);
+        topological_ordering
+    }
 
-        self.value.borrow_mut().grad.replace(T::one());
-        for s in topological_ordering.iter().rev() {
-            if let Some(back_prop) = &s.value.borrow().back_prop {
-                back_prop();
-                let grad = *s.value.borrow().grad.borrow();
-                log::debug!("{}'s gradient: {:.4}", s.value.borrow().label, grad);
+    /// List every node in this Scalar's graph, one per line, after the nodes it was produced from
+    pub fn graph_string(&self) -> String {
+        let ordering = self.topological_order();
+        let ids: HashMap<usize, usize> = ordering.iter().enumerate().map(|(i, s)| (s.id(), i)).collect();
+        let lines: Vec<String> = ordering.iter().enumerate().map(|(i, s)| {
+            let producers: Vec<String> = s.producers().iter().map(|p| format!("#{}", ids[&p.id()])).collect();
+            let op = s.op();
+            match op {
+                Op::Leaf | Op::Constant => format!("#{} = {}: {}", i, op, s),
+                _ => format!("#{} = {}({}): {}", i, op, producers.join(", "), s),
             }
-        }
+        }).collect();
+        lines.join("\n")
     }
 
     pub fn exp(&self) -> Self {
@@ -138,7 +340,9 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
         let out_value = Rc::new(RefCell::new(Value {
             data: Rc::new(RefCell::new(self_data.exp())),
             grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Exp,
             back_prop: None,
+            hooks: Vec::new(),
             producers: producers.into_iter().map(|x| x.value).collect(),
             label: format!("exp({})", self.get_label()).to_string(),
         }));
@@ -150,7 +354,7 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
             *self_grad.borrow_mut() += out_data * out_grad;
         };
         out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
-        Scalar::new_from_value(out_value)
+        Scalar::new_from_op(out_value)
     }
 
     pub fn tanh(&self) -> Self {
@@ -160,7 +364,9 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
         let out_value = Rc::new(RefCell::new(Value {
             data: Rc::new(RefCell::new(self_data.tanh())),
             grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Tanh,
             back_prop: None,
+            hooks: Vec::new(),
             producers: producers.into_iter().map(|x| x.value).collect(),
             label: format!("tanh({})", self.get_label()).to_string(),
         }));
@@ -172,7 +378,33 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
             *self_grad.borrow_mut() += (T::one() - t*t) * out_grad;
         };
         out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
-        Scalar::new_from_value(out_value)
+        Scalar::new_from_op(out_value)
+    }
+
+    pub fn relu(&self) -> Self {
+        let self_data = *self.value.borrow().data.borrow();
+        let self_grad = self.value.borrow().grad.clone();
+        let producers = vec![self.clone()];
+        let out_value = Rc::new(RefCell::new(Value {
+            data: Rc::new(RefCell::new(self_data.max(T::zero()))),
+            grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Relu,
+            back_prop: None,
+            hooks: Vec::new(),
+            producers: producers.into_iter().map(|x| x.value).collect(),
+            label: format!("relu({})", self.get_label()),
+        }));
+
+        let closure_out_value = out_value.clone();
+        let back_prop_closure = move || {
+            let out_data = *closure_out_value.borrow().data.borrow();
+            let out_grad = *closure_out_value.borrow().grad.borrow();
+            if out_data > T::zero() {
+                *self_grad.borrow_mut() += out_grad;
+            }
+        };
+        out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
+        Scalar::new_from_op(out_value)
     }
 
     pub fn pow(&self, power: T) -> Self {
@@ -182,7 +414,9 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
         let out_value = Rc::new(RefCell::new(Value {
             data: Rc::new(RefCell::new(self_data.powf(power))),
             grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Pow(power),
             back_prop: None,
+            hooks: Vec::new(),
             producers: producers.into_iter().map(|x| x.value).collect(),
             label: format!("({}^{})", &self.get_label(), power),
         }));
@@ -195,19 +429,96 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Scalar<T> {
             *self_grad.borrow_mut() += power * self_data.powf(power - T::one()) * out_grad;
         };
         out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
-        Scalar::new_from_value(out_value)
+        Scalar::new_from_op(out_value)
+    }
+
+    /// Apply `op` to `inputs`. Fails if `op.backward` doesn't return one gradient per input.
+    pub fn custom<C: CustomOp<T> + 'static>(op: C, inputs: &[Scalar<T>]) -> std::result::Result<Self, String> {
+        let op = Rc::new(op);
+        let input_data: Vec<T> = inputs.iter().map(|x| x.get_data()).collect();
+        let output = op.forward(&input_data);
+        custom_grads(op.as_ref(), &input_data, output, T::one())?;
+        let input_grads: Vec<Rc<RefCell<T>>> = inputs.iter().map(|x| x.value.borrow().grad.clone()).collect();
+        let input_labels: Vec<String> = inputs.iter().map(|x| x.get_label()).collect();
+        let out_value = Rc::new(RefCell::new(Value {
+            data: Rc::new(RefCell::new(output)),
+            grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Custom(op.clone()),
+            back_prop: None,
+            hooks: Vec::new(),
+            producers: inputs.iter().map(|x| x.value.clone()).collect(),
+            label: format!("{}({})", op.name(), input_labels.join(", ")),
+        }));
+
+        let closure_input_values: Vec<Rc<RefCell<Value<T>>>> = inputs.iter().map(|x| x.value.clone()).collect();
+        let closure_out_value = out_value.clone();
+        let back_prop_closure = move || {
+            let input_data: Vec<T> = closure_input_values.iter().map(|x| *x.borrow().data.borrow()).collect();
+            let out_data = *closure_out_value.borrow().data.borrow();
+            let out_grad = *closure_out_value.borrow().grad.borrow();
+            let grads = custom_grads(op.as_ref(), &input_data, out_data, out_grad).unwrap_or_else(|e| panic!("{}", e));
+            for (input_grad, grad) in input_grads.iter().zip(grads) {
+                *input_grad.borrow_mut() += grad;
+            }
+        };
+        out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
+        Ok(Scalar::new_from_op(out_value))
+    }
+
+    // `weights · inputs + bias` as a single node, rather than a `Mul` and an `Add` per input. Callers check the
+    // lengths match, e.g. `Neuron::forward`. The producers are the weights, the inputs, then the bias if there is one.
+    pub(crate) fn dot(weights: &[Scalar<T>], inputs: &[Scalar<T>], bias: Option<&Scalar<T>>) -> Self {
+        assert_eq!(weights.len(), inputs.len(), "dot of {} weights with {} inputs", weights.len(), inputs.len());
+        let mut sum = bias.map_or(T::zero(), |b| b.get_data());
+        for (weight, input) in weights.iter().zip(inputs.iter()) {
+            sum += weight.get_data() * input.get_data();
+        }
+
+        let producers: Vec<Rc<RefCell<Value<T>>>> = weights.iter().chain(inputs.iter()).chain(bias)
+            .map(|x| x.value.clone())
+            .collect();
+        let out_value = Rc::new(RefCell::new(Value {
+            data: Rc::new(RefCell::new(sum)),
+            grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Dot,
+            back_prop: None,
+            hooks: Vec::new(),
+            producers: producers.clone(),
+            label: "dot".to_string(),
+        }));
+
+        let closure_out_value = out_value.clone();
+        let back_prop_closure = move || {
+            let out_grad = *closure_out_value.borrow().grad.borrow();
+            let (weights, rest) = producers.split_at(producers.len() / 2);
+            let (inputs, bias) = rest.split_at(weights.len());
+            for (weight, input) in weights.iter().zip(inputs.iter()) {
+                let weight_data = *weight.borrow().data.borrow();
+                let input_data = *input.borrow().data.borrow();
+                *weight.borrow().grad.borrow_mut() += input_data * out_grad;
+                *input.borrow().grad.borrow_mut() += weight_data * out_grad;
+            }
+            for bias in bias {
+                *bias.borrow().grad.borrow_mut() += out_grad;
+            }
+        };
+        out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
+        Scalar::new_from_op(out_value)
     }
 
     pub fn add_number(&self, number: T) -> Scalar<T> {
-        let rhs = Scalar::new(number);
-        rhs.set_label(&format!("(Constant {})", number));
-        self + &rhs
+        self + &Scalar::constant(number)
     }
 
     pub fn mul_number(&self, number: T) -> Scalar<T> {
-        let rhs = Scalar::new(number);
-        rhs.set_label(&format!("(Constant {})", number));
-        self * &rhs
+        self * &Scalar::constant(number)
+    }
+
+    // A leaf that stands for a number in an expression, rather than a variable
+    pub(crate) fn constant(number: T) -> Self {
+        let constant = Scalar::new_with_label(number, &format!("(Constant {})", number));
+        constant.value.borrow_mut().op = Op::Constant;
+        constant
     }
 }
 
@@ -259,7 +570,9 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Add<&Scalar<T>>
         let out_value = Rc::new(RefCell::new(Value {
             data: Rc::new(RefCell::new(self_data + rhs_data)),
             grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Add,
             back_prop: None,
+            hooks: Vec::new(),
             producers: producers.into_iter().map(|x| x.value).collect(),
             label: format!("({} + {})", self.get_label(), rhs.get_label()),
         }));
@@ -270,7 +583,7 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Add<&Scalar<T>>
             *rhs_grad.borrow_mut() += *closure_value.borrow().grad.borrow();
         };
         out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
-        Scalar::new_from_value(out_value)
+        Scalar::new_from_op(out_value)
     }
 }
 
@@ -286,6 +599,7 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Div<&Scalar<T>>
 impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Mul<&Scalar<T>> for &Scalar<T> {
     type Output = Scalar<T>;
 
+    #[allow(clippy::suspicious_arithmetic_impl)]
     fn mul(self, rhs: &Scalar<T>) -> Scalar<T> {
         let self_data = *self.value.borrow().data.borrow();
         let rhs_data = *rhs.value.borrow().data.borrow();
@@ -297,7 +611,9 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Mul<&Scalar<T>>
         let out_value = Rc::new(RefCell::new(Value {
             data: Rc::new(RefCell::new(self_data * rhs_data)),
             grad: Rc::new(RefCell::new(T::zero())),
+            op: Op::Mul,
             back_prop: None,
+            hooks: Vec::new(),
             producers: producers.into_iter().map(|x| x.value).collect(),
             label: format!("({} * {})", self.get_label(), rhs.get_label()),
         }));
@@ -313,7 +629,7 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Mul<&Scalar<T>>
             *rhs_grad.borrow_mut() += self_data * out_grad;
         };
         out_value.borrow_mut().back_prop = Some(Box::new(back_prop_closure));
-        Scalar::new_from_value(out_value)
+        Scalar::new_from_op(out_value)
     }
 }
 
@@ -350,4 +666,183 @@ impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Sub<&Scalar<T>>
 //         //     result = &result + s;
 //         // }
 //     }
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns one gradient too few
+    struct Broken;
+
+    impl CustomOp<f64> for Broken {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs.iter().sum()
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64, out_grad: f64) -> Vec<f64> {
+            vec![out_grad; inputs.len() - 1]
+        }
+    }
+
+    #[test]
+    fn backward_accumulates_into_leaves_but_not_interior_nodes() {
+        let x = Scalar::new(3.0);
+        let square = x.pow(2.0);
+        let y = square.mul_number(2.0);
+        y.backward();
+        y.backward();
+        assert_eq!(x.get_grad(), 24.0);
+        assert_eq!(square.get_grad(), 2.0);
+        assert_eq!(y.get_grad(), 1.0);
+    }
+
+    #[test]
+    fn backward_with_scales_every_gradient_by_the_seed() {
+        let x = Scalar::new(3.0);
+        let square = x.pow(2.0);
+        square.backward_with(0.5);
+        assert_eq!(square.get_grad(), 0.5);
+        assert_eq!(x.get_grad(), 3.0);
+    }
+
+    #[test]
+    fn backward_with_on_a_leaf_accumulates() {
+        let x = Scalar::new(3.0);
+        x.backward_with(2.0);
+        x.backward_with(0.5);
+        assert_eq!(x.get_grad(), 2.5);
+    }
+
+    #[test]
+    fn backward_retain_sums_losses_that_share_a_subgraph() {
+        let x = Scalar::new(3.0);
+        let shared = x.mul_number(2.0);
+        let first = shared.pow(2.0);
+        let second = shared.tanh();
+        first.backward_retain(1.0);
+        second.backward_retain(0.5);
+        let shared_grad = 2.0 * 6.0 + 0.5 * (1.0 - 6.0f64.tanh().powi(2));
+        assert_eq!(shared.get_grad(), 0.5 * (1.0 - 6.0f64.tanh().powi(2)));
+        assert!((x.get_grad() - 2.0 * shared_grad).abs() < 1e-12);
+
+        // Retaining adds to the root's gradient rather than replacing it
+        first.backward_retain(1.0);
+        assert_eq!(first.get_grad(), 2.0);
+    }
+
+    #[test]
+    fn custom_rejects_the_wrong_number_of_gradients() {
+        let error = Scalar::custom(Broken, &[Scalar::new(1.0), Scalar::new(2.0)]).err();
+        assert_eq!(error.as_deref(), Some("custom returned 1 gradients for 2 inputs"));
+    }
+
+    #[test]
+    fn hooks_only_see_the_gradient_from_the_current_pass() {
+        let x = Scalar::new(3.0);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let hook_seen = seen.clone();
+        x.register_hook(move |grad: f64| {
+            hook_seen.borrow_mut().push(grad);
+            grad.clamp(-1.0, 1.0)
+        });
+        let square = x.pow(2.0);
+        square.backward();
+        square.backward();
+        assert_eq!(*seen.borrow(), vec![6.0, 6.0]);
+        assert_eq!(x.get_grad(), 2.0);
+    }
+
+    #[test]
+    fn hooks_can_use_their_own_node() {
+        let x = Scalar::new(3.0);
+        let square = x.pow(2.0);
+        let hooked = square.clone();
+        square.register_hook(move |grad| {
+            hooked.set_label("hooked");
+            hooked.register_hook(|grad| grad);
+            -grad
+        });
+        square.backward();
+        assert_eq!(square.get_label(), "hooked");
+        assert_eq!(x.get_grad(), -6.0);
+    }
+
+    #[test]
+    fn backward_retain_keeps_what_the_root_hook_returns() {
+        let x = Scalar::new(3.0);
+        let double = x.mul_number(2.0);
+        double.register_hook(|grad| 2.0 * grad);
+        double.backward_retain(1.0);
+        double.backward_retain(1.0);
+        assert_eq!(double.get_grad(), 4.0);
+        assert_eq!(x.get_grad(), 8.0);
+    }
+
+    // dot starts from the bias (or zero) and adds each product in order, like the chain, so the results are bit for
+    // bit equal
+    #[test]
+    fn dot_matches_a_chain_of_mul_and_add() {
+        for with_bias in [true, false] {
+            let weights: Vec<Scalar<f64>> = [0.3, -1.7, 2.2, 0.05].iter().map(|w| Scalar::new(*w)).collect();
+            let x = Scalar::new(-0.4);
+            // The same input twice, whose gradient must add up
+            let inputs = vec![Scalar::new(1.5), x.clone(), Scalar::new(0.25), x.clone()];
+            let bias = Scalar::new(0.6);
+            let mut scalars = weights.clone();
+            scalars.extend([inputs[0].clone(), x.clone(), inputs[2].clone(), bias.clone()]);
+
+            let results = [true, false].map(|fused| {
+                scalars.iter().for_each(|s| s.zero_grad());
+                let bias = if with_bias { Some(&bias) } else { None };
+                let sum = if fused {
+                    Scalar::dot(&weights, &inputs, bias)
+                } else {
+                    let mut products = weights.iter().zip(inputs.iter()).map(|(w, x)| w * x);
+                    let first = bias.cloned().unwrap_or_else(|| products.next().unwrap());
+                    products.fold(first, |sum, product| &sum + &product)
+                };
+                let output = sum.tanh();
+                output.backward();
+                (output.get_data(), scalars.iter().map(|s| s.get_grad()).collect::<Vec<f64>>())
+            });
+            assert_eq!(results[0], results[1], "with bias: {}", with_bias);
+            assert_eq!(bias.get_grad() == 0.0, !with_bias);
+        }
+    }
+
+    // Anomaly detection applies to every thread, so this reruns the test binary for just this test. Turning it on
+    // here would make other tests running alongside panic.
+    #[test]
+    fn anomaly_detection_reports_the_offending_node_on_every_thread() {
+        const CHILD: &str = "MICRO_GRAD_ANOMALY_TEST";
+        if std::env::var_os(CHILD).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "scalar::tests::anomaly_detection_reports_the_offending_node_on_every_thread"])
+                .env(CHILD, "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(output.status.success() && stdout.contains("1 passed"), "{}", stdout);
+            return;
+        }
+
+        fn panic_message<R>(f: impl FnOnce() -> R) -> String {
+            let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).err().expect("Expected a panic");
+            payload.downcast_ref::<String>().cloned().unwrap_or_default()
+        }
+
+        set_detect_anomaly(true);
+        let leaf = panic_message(|| Scalar::new_with_label(f64::NAN, "x"));
+        let y = Scalar::new_with_label(0.0, "y");
+        let forward = panic_message(|| &Scalar::new(1.0) / &y);
+        let worker = std::thread::spawn(|| {
+            let graph = crate::Graph::new();
+            panic_message(|| graph.var(0.0).pow(-1.0))
+        }).join().unwrap();
+        set_detect_anomaly(false);
+
+        assert_eq!(leaf, "Anomaly detected: created with NaN at 'x' (op: leaf), producers: []");
+        assert!(forward.starts_with("Anomaly detected: forward produced inf at '(y^-1)' (op: ^-1)"), "{}", forward);
+        assert!(worker.starts_with("Anomaly detected: forward produced inf at #1 (op: ^-1)"), "{}", worker);
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+
+use num_traits::Float;
+use serde::{Deserialize, Serialize};
+
+use crate::nn::{Layer, Neuron, NnError};
+use crate::{Activation, MultiLayerPerceptron};
+
+const FORMAT: &str = "micro-grad-mlp";
+
+/// Bump this when the document layout changes, and keep reading the older versions
+pub const JSON_FORMAT_VERSION: u32 = 1;
+
+// Document -------------------------------------------------------------------
+
+// Values are stored as f64 whatever T is. Every f32 is exactly representable as an f64, and serde_json writes floats
+// with enough digits to read back the same bits (its float_roundtrip feature makes the reading exact), so a round trip
+// doesn't change any weight. Reading a value too large for T is an error rather than an infinite weight.
+
+#[derive(Serialize, Deserialize)]
+struct ModelDocument {
+    format: String,
+    version: u32,
+    num_inputs: usize,
+    layers: Vec<LayerDocument>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayerDocument {
+    activation: Activation,
+    neurons: Vec<NeuronDocument>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NeuronDocument {
+    weights: Vec<f64>,
+    bias: Option<f64>,
+}
+
+fn to_f64<T: Float + Display>(value: T, name: &str) -> Result<f64, NnError> {
+    // JSON has no NaN or infinity
+    if !value.is_finite() {
+        Err(NnError::InvalidFormat(format!("Can't save {} = {} as JSON", name, value)))?
+    }
+    Ok(value.to_f64().unwrap())
+}
+
+// A value read from JSON can be finite as an f64 but too large for T, e.g. f32
+fn from_f64<T: Float>(value: f64, name: &str) -> Result<T, NnError> {
+    T::from(value).filter(|v| v.is_finite())
+        .ok_or_else(|| NnError::InvalidFormat(format!("{} = {} is out of range", name, value)))
+}
+
+// MLP ------------------------------------------------------------------------
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> MultiLayerPerceptron<T> {
+    /// The architecture and weights as a versioned JSON document, which `from_json` turns back into the same model
+    pub fn to_json(&self) -> Result<String, NnError> {
+        let layers = self.layers.iter().enumerate().map(|(i, layer)| {
+            let neurons = layer.neurons.iter().enumerate().map(|(j, neuron)| {
+                let name = format!("layers.{}.neurons.{}", i, j);
+                Ok(NeuronDocument {
+                    weights: neuron.weights.iter().map(|w| to_f64(w.get_data(), &name)).collect::<Result<_, _>>()?,
+                    bias: neuron.bias.as_ref().map(|b| to_f64(b.get_data(), &name)).transpose()?,
+                })
+            }).collect::<Result<_, NnError>>()?;
+            let activation = layer.neurons.first().map_or(Activation::Tanh, |n| n.activation);
+            Ok(LayerDocument { activation, neurons })
+        }).collect::<Result<_, NnError>>()?;
+
+        let document = ModelDocument {
+            format: FORMAT.to_string(),
+            version: JSON_FORMAT_VERSION,
+            num_inputs: self.architecture().num_inputs,
+            layers,
+        };
+        serde_json::to_string_pretty(&document).map_err(|e| NnError::InvalidFormat(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, NnError> {
+        let document: ModelDocument = serde_json::from_str(json).map_err(|e| NnError::InvalidFormat(e.to_string()))?;
+        if document.format != FORMAT {
+            Err(NnError::InvalidFormat(format!("Expected a {} document, not {}", FORMAT, document.format)))?
+        }
+        if document.version != JSON_FORMAT_VERSION {
+            Err(NnError::InvalidFormat(format!("Unsupported version {}, expected {}",
+                                               document.version, JSON_FORMAT_VERSION)))?
+        }
+        if let Some(first) = document.layers.first().and_then(|l| l.neurons.first()) {
+            if first.weights.len() != document.num_inputs {
+                Err(NnError::ShapeMismatch { expected: document.num_inputs, actual: first.weights.len() })?
+            }
+        }
+
+        let layers = document.layers.iter().enumerate().map(|(i, layer)| {
+            let neurons = layer.neurons.iter().enumerate().map(|(j, neuron)| {
+                let name = format!("layers.{}.neurons.{}", i, j);
+                let weights: Vec<T> = neuron.weights.iter().map(|w| from_f64(*w, &name)).collect::<Result<_, _>>()?;
+                let bias = neuron.bias.map(|b| from_f64(b, &name)).transpose()?;
+                Ok(Neuron::from_values(&weights, bias, layer.activation))
+            }).collect::<Result<_, NnError>>()?;
+            Ok(Layer::from_neurons(neurons))
+        }).collect::<Result<_, NnError>>()?;
+        MultiLayerPerceptron::from_layers(layers)
+    }
+
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> Result<(), NnError> {
+        fs::write(path, self.to_json()?)?;
+        Ok(())
+    }
+
+    pub fn load_json<P: AsRef<Path>>(path: P) -> Result<Self, NnError> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Scalar};
+
+    fn trained<T: Float + Copy + Display + std::ops::AddAssign + 'static>() -> MultiLayerPerceptron<T> {
+        let mlp = MultiLayerPerceptron::builder(3).layers(&[4, 4, 1]).output_activation(Activation::Sigmoid)
+            .seed(3).build().unwrap();
+        let xs: Vec<Vec<T>> = [[2.0, 3.0, -1.0], [3.0, -1.0, 0.5], [0.5, 1.0, 1.0]].iter()
+            .map(|x| x.iter().map(|v| T::from(*v).unwrap()).collect())
+            .collect();
+        for _ in 0..20 {
+            let predictions = mlp.forward_batch(&xs).unwrap().concat();
+            let loss = predictions.iter().fold(Scalar::new(T::zero()), |sum, p| &sum + &p.pow(T::from(2).unwrap()));
+            mlp.zero_grad();
+            loss.backward();
+            for p in mlp.parameters() {
+                p.add_to_data(p.get_grad() * T::from(-0.1).unwrap());
+            }
+        }
+        mlp
+    }
+
+    fn assert_round_trip<T: Float + Copy + Display + std::ops::AddAssign + 'static>(to_bits: fn(T) -> u64) {
+        let mlp = trained::<T>();
+        let loaded = MultiLayerPerceptron::<T>::from_json(&mlp.to_json().unwrap()).unwrap();
+        for x in [[0.5, -1.0, 2.0], [1.0 / 3.0, 0.1, -0.7]] {
+            let x: Vec<T> = x.iter().map(|v| T::from(*v).unwrap()).collect();
+            let bits = |outputs: Vec<T>| outputs.into_iter().map(to_bits).collect::<Vec<u64>>();
+            assert_eq!(bits(loaded.predict(&x).unwrap()), bits(mlp.predict(&x).unwrap()));
+        }
+    }
+
+    #[test]
+    fn round_trips_keep_predictions_bit_for_bit() {
+        assert_round_trip::<f32>(|v| v.to_bits() as u64);
+        assert_round_trip::<f64>(f64::to_bits);
+    }
+
+    #[test]
+    fn values_out_of_range_for_f32_are_rejected() {
+        let mlp = trained::<f64>();
+        mlp.parameters()[0].add_to_data(1e300);
+        let json = mlp.to_json().unwrap();
+        let error = MultiLayerPerceptron::<f32>::from_json(&json).err().unwrap();
+        assert!(matches!(error, NnError::InvalidFormat(message) if message.contains("layers.0.neurons.0")));
+        assert!(MultiLayerPerceptron::<f64>::from_json(&json).is_ok());
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result};
+
+use num_traits::Float;
+
+use crate::scalar::Op;
+use crate::Scalar;
+
+/// A symbolic expression, built from the graph behind a `Scalar`. Leaves become variables named by their labels, and
+/// unlabelled leaves and constants become numbers. Expressions built through `derivative` and `simplify` have
+/// trivial terms removed (`x * 1` → `x`, `x + 0` → `x`) and powers of the same base combined. Only non-negative
+/// powers are combined, since `x * x^-1` is undefined at `x = 0` rather than 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr<T> {
+    Constant(T),
+    Variable(String),
+    Add(Box<Expr<T>>, Box<Expr<T>>),
+    Mul(Box<Expr<T>>, Box<Expr<T>>),
+    Pow(Box<Expr<T>>, T),
+    Exp(Box<Expr<T>>),
+    Tanh(Box<Expr<T>>),
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Expr<T> {
+    /// The expression computed by `scalar`'s graph. Custom ops and relu have no symbolic form, so they are an error.
+    pub fn from_scalar(scalar: &Scalar<T>) -> std::result::Result<Self, String> {
+        let mut exprs: HashMap<usize, Expr<T>> = HashMap::new();
+        for s in scalar.topological_order() {
+            let producers: Vec<Expr<T>> = s.producers().iter().map(|p| exprs[&p.id()].clone()).collect();
+            let expr = match s.op() {
+                Op::Leaf if !s.get_label().is_empty() => Expr::Variable(s.get_label()),
+                Op::Leaf | Op::Constant => Expr::Constant(s.get_data()),
+                Op::Add => Expr::Add(Box::new(producers[0].clone()), Box::new(producers[1].clone())),
+                Op::Mul => Expr::Mul(Box::new(producers[0].clone()), Box::new(producers[1].clone())),
+                Op::Pow(power) => Expr::Pow(Box::new(producers[0].clone()), power),
+                Op::Exp => Expr::Exp(Box::new(producers[0].clone())),
+                Op::Tanh => Expr::Tanh(Box::new(producers[0].clone())),
+                Op::Dot => {
+                    // Weights, inputs, then the bias if the dot has one
+                    let n = producers.len() / 2;
+                    let mut products = producers[..n].iter().zip(producers[n..2 * n].iter())
+                        .map(|(weight, input)| Expr::Mul(Box::new(weight.clone()), Box::new(input.clone())));
+                    let mut sum = producers.get(2 * n).cloned().or_else(|| products.next()).unwrap();
+                    for product in products {
+                        sum = Expr::Add(Box::new(sum), Box::new(product));
+                    }
+                    sum
+                }
+                Op::Relu => Err("relu has no symbolic form")?,
+                Op::Custom(op) => Err(format!("{} has no symbolic form", op.name()))?,
+            };
+            exprs.insert(s.id(), expr);
+        }
+        Ok(exprs.remove(&scalar.id()).unwrap())
+    }
+
+    /// The simplified derivative of `output` with respect to `leaf`, which must have a label no other leaf shares
+    pub fn gradient(output: &Scalar<T>, leaf: &Scalar<T>) -> std::result::Result<Self, String> {
+        let label = leaf.get_label();
+        if label.is_empty() {
+            Err("Can only differentiate with respect to a labelled leaf")?
+        }
+        let namesakes = output.topological_order().iter()
+            .filter(|s| matches!(s.op(), Op::Leaf) && s.get_label() == label && s.id() != leaf.id())
+            .count();
+        if namesakes > 0 {
+            Err(format!("{} other leaves are also labelled {}", namesakes, label))?
+        }
+        Ok(Self::from_scalar(output)?.derivative(&label))
+    }
+
+    pub fn derivative(&self, variable: &str) -> Self {
+        match self {
+            Expr::Constant(_) => Expr::Constant(T::zero()),
+            Expr::Variable(name) => Expr::Constant(if name == variable { T::one() } else { T::zero() }),
+            Expr::Add(a, b) => Expr::add(a.derivative(variable), b.derivative(variable)),
+            Expr::Mul(a, b) => Expr::add(
+                Expr::mul(a.derivative(variable), b.simplify()),
+                Expr::mul(a.simplify(), b.derivative(variable))),
+            Expr::Pow(a, power) => Expr::mul(
+                Expr::mul(Expr::Constant(*power), Expr::pow(a.simplify(), *power - T::one())),
+                a.derivative(variable)),
+            Expr::Exp(a) => Expr::mul(Expr::exp(a.simplify()), a.derivative(variable)),
+            Expr::Tanh(a) => {
+                let tanh_squared = Expr::pow(Expr::tanh(a.simplify()), T::from(2).unwrap());
+                let local = Expr::add(Expr::Constant(T::one()), Expr::mul(Expr::Constant(-T::one()), tanh_squared));
+                Expr::mul(local, a.derivative(variable))
+            }
+        }
+    }
+
+    pub fn simplify(&self) -> Self {
+        match self {
+            Expr::Constant(_) | Expr::Variable(_) => self.clone(),
+            Expr::Add(a, b) => Expr::add(a.simplify(), b.simplify()),
+            Expr::Mul(a, b) => Expr::mul(a.simplify(), b.simplify()),
+            Expr::Pow(a, power) => Expr::pow(a.simplify(), *power),
+            Expr::Exp(a) => Expr::exp(a.simplify()),
+            Expr::Tanh(a) => Expr::tanh(a.simplify()),
+        }
+    }
+
+    pub fn evaluate(&self, variables: &HashMap<String, T>) -> std::result::Result<T, String> {
+        let value = match self {
+            Expr::Constant(c) => *c,
+            Expr::Variable(name) => *variables.get(name).ok_or(format!("No value for {}", name))?,
+            Expr::Add(a, b) => a.evaluate(variables)? + b.evaluate(variables)?,
+            Expr::Mul(a, b) => a.evaluate(variables)? * b.evaluate(variables)?,
+            Expr::Pow(a, power) => a.evaluate(variables)?.powf(*power),
+            Expr::Exp(a) => a.evaluate(variables)?.exp(),
+            Expr::Tanh(a) => a.evaluate(variables)?.tanh(),
+        };
+        Ok(value)
+    }
+
+    // Constructors that apply the simplification rules to already simplified operands
+
+    fn add(a: Self, b: Self) -> Self {
+        match (a, b) {
+            (Expr::Constant(x), Expr::Constant(y)) => Expr::Constant(x + y),
+            (Expr::Constant(zero), other) | (other, Expr::Constant(zero)) if zero.is_zero() => other,
+            // Keep constants and negations on the right, for x + 1 and 1 - x
+            (a, b) if a.negated().is_some() && b.negated().is_none() => Expr::Add(Box::new(b), Box::new(a)),
+            (constant @ Expr::Constant(_), other) if other.negated().is_none() => {
+                Expr::Add(Box::new(other), Box::new(constant))
+            }
+            (a, b) if a == b => Expr::mul(Expr::Constant(T::from(2).unwrap()), a),
+            (a, b) => Expr::Add(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn mul(a: Self, b: Self) -> Self {
+        match (a, b) {
+            (Expr::Constant(x), Expr::Constant(y)) => Expr::Constant(x * y),
+            (Expr::Constant(zero), _) | (_, Expr::Constant(zero)) if zero.is_zero() => Expr::Constant(T::zero()),
+            (Expr::Constant(one), other) | (other, Expr::Constant(one)) if one.is_one() => other,
+            // Keep constants on the left, for 2 * x, and gather them together
+            (other, constant @ Expr::Constant(_)) => Expr::mul(constant, other),
+            (Expr::Constant(x), Expr::Mul(y, other)) if matches!(*y, Expr::Constant(_)) => {
+                Expr::mul(Expr::mul(Expr::Constant(x), *y), *other)
+            }
+            (Expr::Mul(x, a), b) if matches!(*x, Expr::Constant(_)) => Expr::mul(*x, Expr::mul(*a, b)),
+            (a, b) if a.base() == b.base() && a.exponent() >= T::zero() && b.exponent() >= T::zero() => {
+                let (base, a_power) = a.into_power();
+                let (_, b_power) = b.into_power();
+                Expr::pow(base, a_power + b_power)
+            }
+            (a, b) => Expr::Mul(Box::new(a), Box::new(b)),
+        }
+    }
+
+    fn pow(a: Self, power: T) -> Self {
+        match a {
+            _ if power.is_zero() => Expr::Constant(T::one()),
+            a if power.is_one() => a,
+            Expr::Constant(x) => Expr::Constant(x.powf(power)),
+            Expr::Pow(base, inner) => Expr::pow(*base, inner * power),
+            a => Expr::Pow(Box::new(a), power),
+        }
+    }
+
+    fn exp(a: Self) -> Self {
+        match a {
+            Expr::Constant(x) => Expr::Constant(x.exp()),
+            a => Expr::Exp(Box::new(a)),
+        }
+    }
+
+    fn tanh(a: Self) -> Self {
+        match a {
+            Expr::Constant(x) => Expr::Constant(x.tanh()),
+            a => Expr::Tanh(Box::new(a)),
+        }
+    }
+
+    // x^n has base x; anything else is its own base
+    fn base(&self) -> &Self {
+        match self {
+            Expr::Pow(base, _) => base,
+            other => other,
+        }
+    }
+
+    fn exponent(&self) -> T {
+        match self {
+            Expr::Pow(_, power) => *power,
+            _ => T::one(),
+        }
+    }
+
+    fn into_power(self) -> (Self, T) {
+        match self {
+            Expr::Pow(base, power) => (*base, power),
+            other => (other, T::one()),
+        }
+    }
+}
+
+// Binding strength, used to decide where parentheses are needed
+const SUM: u8 = 1;
+const PRODUCT: u8 = 2;
+const POWER: u8 = 3;
+const ATOM: u8 = 4;
+
+impl<T: Float + Display> Expr<T> {
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Constant(c) if *c < T::zero() => SUM,
+            Expr::Add(..) => SUM,
+            Expr::Mul(..) => PRODUCT,
+            Expr::Pow(..) => POWER,
+            _ => ATOM,
+        }
+    }
+
+    fn fmt_at(&self, f: &mut Formatter<'_>, precedence: u8) -> Result {
+        if self.precedence() < precedence {
+            write!(f, "(")?;
+            self.fmt_at(f, 0)?;
+            return write!(f, ")");
+        }
+
+        match self {
+            Expr::Constant(c) => write!(f, "{}", c),
+            Expr::Variable(name) => write!(f, "{}", name),
+            Expr::Add(a, b) => {
+                a.fmt_at(f, SUM)?;
+                match b.negated() {
+                    Some(negated) => {
+                        write!(f, " - ")?;
+                        negated.fmt_at(f, PRODUCT)
+                    }
+                    None => {
+                        write!(f, " + ")?;
+                        b.fmt_at(f, SUM)
+                    }
+                }
+            }
+            Expr::Mul(a, b) => {
+                match a.as_ref() {
+                    Expr::Constant(c) if *c == -T::one() => write!(f, "-")?,
+                    a => {
+                        a.fmt_at(f, PRODUCT)?;
+                        if let Expr::Pow(base, power) = b.as_ref() {
+                            if *power < T::zero() {
+                                write!(f, " / ")?;
+                                return match (-*power).is_one() {
+                                    true => base.fmt_at(f, ATOM),
+                                    false => write!(f, "{}^{}", AtPrecedence(base, ATOM), -*power),
+                                };
+                            }
+                        }
+                        write!(f, " * ")?;
+                    }
+                }
+                b.fmt_at(f, PRODUCT)
+            }
+            Expr::Pow(a, power) => {
+                a.fmt_at(f, ATOM)?;
+                write!(f, "^{}", power)
+            }
+            Expr::Exp(a) => write!(f, "exp({})", a),
+            Expr::Tanh(a) => write!(f, "tanh({})", a),
+        }
+    }
+
+    // If this is -x or a negative constant, its magnitude, so a sum can print as a - b
+    fn negated(&self) -> Option<Expr<T>> {
+        match self {
+            Expr::Constant(c) if *c < T::zero() => Some(Expr::Constant(-*c)),
+            Expr::Mul(a, b) if matches!(a.as_ref(), Expr::Constant(c) if *c == -T::one()) => Some(b.as_ref().clone()),
+            _ => None,
+        }
+    }
+}
+
+struct AtPrecedence<'a, T>(&'a Expr<T>, u8);
+
+impl<T: Float + Display> Display for AtPrecedence<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.0.fmt_at(f, self.1)
+    }
+}
+
+impl<T: Float + Display> Display for Expr<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.fmt_at(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(name: &str) -> Scalar<f64> {
+        let scalar = Scalar::new(0.5);
+        scalar.set_label(name);
+        scalar
+    }
+
+    fn simplified(scalar: &Scalar<f64>) -> String {
+        Expr::from_scalar(scalar).unwrap().simplify().to_string()
+    }
+
+    #[test]
+    fn simplify_removes_trivial_terms() {
+        let x = variable("x");
+        assert_eq!(simplified(&x.mul_number(1.0).add_number(0.0)), "x");
+        assert_eq!(simplified(&(&x.mul_number(0.0) + &x.pow(1.0))), "x");
+        assert_eq!(simplified(&x.pow(0.0)), "1");
+        assert_eq!(simplified(&(&x + &x)), "2 * x");
+        assert_eq!(simplified(&x.mul_number(2.0).mul_number(3.0)), "6 * x");
+        assert_eq!(simplified(&x.add_number(-1.0)), "x - 1");
+        assert_eq!(simplified(&x.pow(2.0).pow(3.0)), "x^6");
+    }
+
+    #[test]
+    fn simplify_only_combines_non_negative_powers() {
+        let x = variable("x");
+        assert_eq!(simplified(&(&x * &x.pow(2.0))), "x^3");
+        assert_eq!(simplified(&(&x.pow(0.5) * &x.pow(0.5))), "x");
+        assert_eq!(simplified(&(&x / &x)), "x / x");
+        assert_eq!(simplified(&(&x.pow(3.0) / &x)), "x^3 / x");
+        assert_eq!(simplified(&(&x.pow(-1.0) * &x.pow(-2.0))), "x^-1 / x^2");
+    }
+
+    #[test]
+    fn derivative_rules() {
+        let x = variable("x");
+        let y = variable("y");
+        let gradient = |output: &Scalar<f64>| Expr::gradient(output, &x).unwrap().to_string();
+        assert_eq!(gradient(&y), "0");
+        assert_eq!(gradient(&(&x + &y)), "1");
+        assert_eq!(gradient(&(&x * &y)), "y");
+        assert_eq!(gradient(&x.pow(3.0)), "3 * x^2");
+        assert_eq!(gradient(&x.pow(-1.0)), "-x^-2");
+        assert_eq!(gradient(&x.mul_number(2.0).exp()), "2 * exp(2 * x)");
+        assert_eq!(gradient(&x.tanh()), "1 - tanh(x)^2");
+    }
+
+    #[test]
+    fn symbolic_gradients_match_backward() {
+        let x = variable("x");
+        let y = variable("y");
+        y.add_to_data(-2.0);
+        let output = (&(&x * &y) + &x.pow(3.0)).tanh().add_number(1.0);
+        output.backward();
+        let variables = HashMap::from([("x".to_string(), x.get_data()), ("y".to_string(), y.get_data())]);
+        for leaf in [&x, &y] {
+            let value = Expr::gradient(&output, leaf).unwrap().evaluate(&variables).unwrap();
+            assert!((value - leaf.get_grad()).abs() < 1e-12, "{} != {}", value, leaf.get_grad());
+        }
+    }
+
+    #[test]
+    fn relu_has_no_symbolic_form() {
+        assert_eq!(Expr::from_scalar(&variable("x").relu()).err().as_deref(), Some("relu has no symbolic form"));
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use num_traits::Float;
+
+use crate::scalar::{custom_grads, CustomOp, Op};
+use crate::Scalar;
+
+// One step of the tape. Operands are indices of earlier instructions.
+enum Instruction<T> {
+    // An input, parameter or constant; its value is written from outside the tape
+    Leaf,
+    Add(usize, usize),
+    Mul(usize, usize),
+    Pow(usize, T),
+    Exp(usize),
+    Tanh(usize),
+    Relu(usize),
+    // Weights, inputs and an optional bias
+    Dot(Vec<usize>, Vec<usize>, Option<usize>),
+    Custom(Rc<dyn CustomOp<T>>, Vec<usize>),
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Instruction<T> {
+    // The instruction's value, given the values of earlier instructions. Leaves have no value to compute.
+    fn evaluate(&self, data: &[T], scratch: &mut Vec<T>) -> Option<T> {
+        let value = match self {
+            Instruction::Leaf => return None,
+            Instruction::Add(a, b) => data[*a] + data[*b],
+            Instruction::Mul(a, b) => data[*a] * data[*b],
+            Instruction::Pow(a, power) => data[*a].powf(*power),
+            Instruction::Exp(a) => data[*a].exp(),
+            Instruction::Tanh(a) => data[*a].tanh(),
+            Instruction::Relu(a) => data[*a].max(T::zero()),
+            Instruction::Dot(weights, inputs, bias) => {
+                let mut sum = bias.map_or(T::zero(), |b| data[b]);
+                for (w, x) in weights.iter().zip(inputs.iter()) {
+                    sum += data[*w] * data[*x];
+                }
+                sum
+            }
+            Instruction::Custom(op, operands) => {
+                scratch.clear();
+                scratch.extend(operands.iter().map(|o| data[*o]));
+                op.forward(scratch)
+            }
+        };
+        Some(value)
+    }
+
+    fn operands(&self) -> Vec<usize> {
+        match self {
+            Instruction::Leaf => Vec::new(),
+            Instruction::Add(a, b) | Instruction::Mul(a, b) => vec![*a, *b],
+            Instruction::Pow(a, _) | Instruction::Exp(a) | Instruction::Tanh(a) | Instruction::Relu(a) => vec![*a],
+            Instruction::Dot(weights, inputs, bias) => {
+                weights.iter().chain(inputs.iter()).chain(bias.iter()).copied().collect()
+            }
+            Instruction::Custom(_, operands) => operands.clone(),
+        }
+    }
+
+    fn map_operands(&self, map: &[usize]) -> Self {
+        match self {
+            Instruction::Leaf => Instruction::Leaf,
+            Instruction::Add(a, b) => Instruction::Add(map[*a], map[*b]),
+            Instruction::Mul(a, b) => Instruction::Mul(map[*a], map[*b]),
+            Instruction::Pow(a, power) => Instruction::Pow(map[*a], *power),
+            Instruction::Exp(a) => Instruction::Exp(map[*a]),
+            Instruction::Tanh(a) => Instruction::Tanh(map[*a]),
+            Instruction::Relu(a) => Instruction::Relu(map[*a]),
+            Instruction::Dot(weights, inputs, bias) => Instruction::Dot(
+                weights.iter().map(|w| map[*w]).collect(),
+                inputs.iter().map(|x| map[*x]).collect(),
+                bias.map(|b| map[b])),
+            Instruction::Custom(op, operands) => Instruction::Custom(op.clone(), operands.iter().map(|o| map[*o]).collect()),
+        }
+    }
+
+    // Identifies instructions that always compute the same value, with operands of commutative operations sorted.
+    // Custom ops might not be pure, so they have no key and are never merged.
+    fn key(&self, value: T, is_constant: bool) -> Option<Key> {
+        let key = match self {
+            Instruction::Leaf if is_constant => {
+                let (mantissa, exponent, sign) = value.integer_decode();
+                Key::Constant(mantissa, exponent, sign)
+            }
+            Instruction::Leaf | Instruction::Custom(..) => return None,
+            Instruction::Add(a, b) => Key::Add(*a.min(b), *a.max(b)),
+            Instruction::Mul(a, b) => Key::Mul(*a.min(b), *a.max(b)),
+            Instruction::Pow(a, power) => Key::Pow(*a, power.integer_decode()),
+            Instruction::Exp(a) => Key::Exp(*a),
+            Instruction::Tanh(a) => Key::Tanh(*a),
+            Instruction::Relu(a) => Key::Relu(*a),
+            Instruction::Dot(weights, inputs, bias) => Key::Dot(weights.clone(), inputs.clone(), *bias),
+        };
+        Some(key)
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum Key {
+    Constant(u64, i16, i8),
+    Add(usize, usize),
+    Mul(usize, usize),
+    Pow(usize, (u64, i16, i8)),
+    Exp(usize),
+    Tanh(usize),
+    Relu(usize),
+    Dot(Vec<usize>, Vec<usize>, Option<usize>),
+}
+
+/// A `Scalar` graph flattened into a list of instructions over indices (a Wengert list). The graph is traced once,
+/// after which `forward` and `backward` can be re-run with new input values without rebuilding or allocating nodes.
+///
+/// Parameters are leaves whose values are copied from their original `Scalar`s on `refresh_parameters`, and whose
+/// gradients are reported by `parameter_grads`. Any other leaf is a constant, fixed when the tape is traced. Hooks
+/// and anomaly detection don't apply to the tape.
+pub struct Tape<T> {
+    instructions: Vec<Instruction<T>>,
+    data: Vec<T>,
+    grads: Vec<T>,
+    inputs: HashMap<String, usize>,
+    parameters: Vec<(usize, Scalar<T>)>,
+    output: usize,
+    // Reused to pass operands to custom ops
+    scratch: Vec<T>,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + 'static> Tape<T> {
+    /// Trace the graph that produces `output`. Each named input and parameter must be a leaf of that graph, and each
+    /// custom op must return one gradient per input.
+    pub fn trace(output: &Scalar<T>, inputs: &[(&str, &Scalar<T>)], parameters: &[Scalar<T>]) -> Result<Self, String> {
+        let ordering = output.topological_order();
+        let indices: HashMap<usize, usize> = ordering.iter().enumerate().map(|(i, s)| (s.id(), i)).collect();
+
+        let mut tape = Tape {
+            instructions: Vec::with_capacity(ordering.len()),
+            data: ordering.iter().map(|s| s.get_data()).collect(),
+            grads: vec![T::zero(); ordering.len()],
+            inputs: HashMap::new(),
+            parameters: Vec::new(),
+            output: ordering.len() - 1,
+            scratch: Vec::new(),
+        };
+
+        for (name, input) in inputs {
+            match indices.get(&input.id()) {
+                Some(&index) if matches!(input.op(), Op::Leaf) => {
+                    tape.inputs.insert(name.to_string(), index);
+                }
+                Some(_) => Err(format!("Input {} is not a leaf", name))?,
+                None => Err(format!("Input {} is not part of the graph", name))?,
+            }
+        }
+        for parameter in parameters {
+            match indices.get(&parameter.id()) {
+                Some(&index) if matches!(parameter.op(), Op::Leaf) => tape.parameters.push((index, parameter.clone())),
+                Some(_) => Err(format!("Parameter {} is not a leaf", parameter.get_label()))?,
+                None => Err(format!("Parameter {} is not part of the graph", parameter.get_label()))?,
+            }
+        }
+
+        for s in ordering.iter() {
+            let operands: Vec<usize> = s.producers().iter().map(|p| indices[&p.id()]).collect();
+            let instruction = match s.op() {
+                Op::Leaf | Op::Constant => Instruction::Leaf,
+                Op::Add => Instruction::Add(operands[0], operands[1]),
+                Op::Mul => Instruction::Mul(operands[0], operands[1]),
+                Op::Pow(power) => Instruction::Pow(operands[0], power),
+                Op::Exp => Instruction::Exp(operands[0]),
+                Op::Tanh => Instruction::Tanh(operands[0]),
+                Op::Relu => Instruction::Relu(operands[0]),
+                Op::Dot => {
+                    let n = operands.len() / 2;
+                    Instruction::Dot(operands[..n].to_vec(), operands[n..2 * n].to_vec(), operands.get(2 * n).copied())
+                }
+                Op::Custom(op) => {
+                    let inputs: Vec<T> = operands.iter().map(|o| tape.data[*o]).collect();
+                    let index = tape.instructions.len();
+                    custom_grads(op.as_ref(), &inputs, tape.data[index], T::one())?;
+                    Instruction::Custom(op, operands)
+                }
+            };
+            tape.instructions.push(instruction);
+        }
+        Ok(tape)
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn set_input(&mut self, name: &str, value: T) -> Result<(), String> {
+        let index = self.inputs.get(name).ok_or(format!("Unknown input {}", name))?;
+        self.data[*index] = value;
+        Ok(())
+    }
+
+    /// Copy the current value of every parameter from its `Scalar`, e.g. after an optimizer step
+    pub fn refresh_parameters(&mut self) {
+        for (index, parameter) in &self.parameters {
+            self.data[*index] = parameter.get_data();
+        }
+    }
+
+    pub fn forward(&mut self) -> T {
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            if let Some(value) = instruction.evaluate(&self.data, &mut self.scratch) {
+                self.data[i] = value;
+            }
+        }
+        self.data[self.output]
+    }
+
+    /// Compute the gradient of the output with respect to every node. Unlike `Scalar::backward`, gradients don't
+    /// accumulate across calls.
+    pub fn backward(&mut self) {
+        let data = &self.data;
+        let grads = &mut self.grads;
+        grads.fill(T::zero());
+        grads[self.output] = T::one();
+        for (i, instruction) in self.instructions.iter().enumerate().rev() {
+            let out_grad = grads[i];
+            match instruction {
+                Instruction::Leaf => {}
+                Instruction::Add(a, b) => {
+                    grads[*a] += out_grad;
+                    grads[*b] += out_grad;
+                }
+                Instruction::Mul(a, b) => {
+                    grads[*a] += data[*b] * out_grad;
+                    grads[*b] += data[*a] * out_grad;
+                }
+                Instruction::Pow(a, power) => grads[*a] += *power * data[*a].powf(*power - T::one()) * out_grad,
+                Instruction::Exp(a) => grads[*a] += data[i] * out_grad,
+                Instruction::Tanh(a) => grads[*a] += (T::one() - data[i] * data[i]) * out_grad,
+                Instruction::Relu(a) => {
+                    if data[i] > T::zero() {
+                        grads[*a] += out_grad;
+                    }
+                }
+                Instruction::Dot(weights, inputs, bias) => {
+                    for (w, x) in weights.iter().zip(inputs.iter()) {
+                        grads[*w] += data[*x] * out_grad;
+                        grads[*x] += data[*w] * out_grad;
+                    }
+                    if let Some(bias) = bias {
+                        grads[*bias] += out_grad;
+                    }
+                }
+                Instruction::Custom(op, operands) => {
+                    self.scratch.clear();
+                    self.scratch.extend(operands.iter().map(|o| data[*o]));
+                    let op_grads = custom_grads(op.as_ref(), &self.scratch, data[i], out_grad)
+                        .unwrap_or_else(|e| panic!("{}", e));
+                    for (o, grad) in operands.iter().zip(op_grads) {
+                        grads[*o] += grad;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Simplify the tape without changing its output or any input or parameter gradient. Instructions whose operands
+    /// are all constants are replaced by their value, identical instructions are merged, and instructions that no
+    /// longer contribute to the output are removed.
+    pub fn optimize(&mut self) {
+        let mut is_constant: Vec<bool> = self.instructions.iter().map(|i| matches!(i, Instruction::Leaf)).collect();
+        for index in self.inputs.values().chain(self.parameters.iter().map(|(index, _)| index)) {
+            is_constant[*index] = false;
+        }
+
+        // Fold constants and merge common subexpressions. map[i] is the new index of the old instruction i.
+        let mut instructions: Vec<Instruction<T>> = Vec::new();
+        let mut data: Vec<T> = Vec::new();
+        let mut constant: Vec<bool> = Vec::new();
+        let mut keys: HashMap<Key, usize> = HashMap::new();
+        let mut map = vec![0; self.instructions.len()];
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            let mut instruction = instruction.map_operands(&map);
+            let mut value = self.data[i];
+            let operands = instruction.operands();
+            let foldable = !matches!(instruction, Instruction::Custom(..)) && operands.iter().all(|o| constant[*o]);
+            if !operands.is_empty() && foldable {
+                value = instruction.evaluate(&data, &mut self.scratch).unwrap();
+                instruction = Instruction::Leaf;
+                is_constant[i] = true;
+            }
+
+            let key = instruction.key(value, is_constant[i]);
+            if let Some(existing) = key.as_ref().and_then(|key| keys.get(key)) {
+                map[i] = *existing;
+                continue;
+            }
+            map[i] = instructions.len();
+            if let Some(key) = key {
+                keys.insert(key, map[i]);
+            }
+            instructions.push(instruction);
+            data.push(value);
+            constant.push(is_constant[i]);
+        }
+
+        // Remove instructions that the output doesn't depend on, keeping every input and parameter
+        let output = map[self.output];
+        let mut live = vec![false; instructions.len()];
+        live[output] = true;
+        for index in self.inputs.values().chain(self.parameters.iter().map(|(index, _)| index)) {
+            live[map[*index]] = true;
+        }
+        for i in (0..instructions.len()).rev() {
+            if live[i] {
+                for o in instructions[i].operands() {
+                    live[o] = true;
+                }
+            }
+        }
+        let mut compacted = vec![0; instructions.len()];
+        let mut next = 0;
+        for i in 0..instructions.len() {
+            compacted[i] = next;
+            next += live[i] as usize;
+        }
+
+        self.instructions = instructions.iter().zip(live.iter()).filter(|(_, live)| **live)
+            .map(|(instruction, _)| instruction.map_operands(&compacted))
+            .collect();
+        self.data = data.iter().zip(live.iter()).filter(|(_, live)| **live).map(|(d, _)| *d).collect();
+        self.grads = vec![T::zero(); self.instructions.len()];
+        self.output = compacted[output];
+        for index in self.inputs.values_mut() {
+            *index = compacted[map[*index]];
+        }
+        for (index, _) in self.parameters.iter_mut() {
+            *index = compacted[map[*index]];
+        }
+    }
+
+    pub fn output(&self) -> T {
+        self.data[self.output]
+    }
+
+    pub fn grad(&self, name: &str) -> Option<T> {
+        self.inputs.get(name).map(|index| self.grads[*index])
+    }
+
+    /// Each parameter, paired with its gradient from the last `backward`
+    pub fn parameter_grads(&self) -> impl Iterator<Item = (&Scalar<T>, T)> + '_ {
+        self.parameters.iter().map(|(index, parameter)| (parameter, self.grads[*index]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    // Returns the right number of gradients the first time only
+    struct Flaky {
+        calls: Cell<usize>,
+    }
+
+    impl CustomOp<f64> for Flaky {
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs.iter().product()
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64, out_grad: f64) -> Vec<f64> {
+            self.calls.set(self.calls.get() + 1);
+            let count = if self.calls.get() == 1 { inputs.len() } else { 1 };
+            vec![out_grad; count]
+        }
+    }
+
+    #[test]
+    fn trace_rejects_custom_ops_with_the_wrong_number_of_gradients() {
+        let x = Scalar::new(2.0);
+        let y = Scalar::new(3.0);
+        let product = Scalar::custom(Flaky { calls: Cell::new(0) }, &[x.clone(), y.clone()]).unwrap();
+        let error = Tape::trace(&product, &[("x", &x), ("y", &y)], &[]).err();
+        assert_eq!(error.as_deref(), Some("custom returned 1 gradients for 2 inputs"));
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() <= 1e-12 * expected.abs().max(1.0), "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn optimize_keeps_the_output_and_gradients() {
+        let x = Scalar::new(0.5);
+        let y = Scalar::new(-1.5);
+        let w = Scalar::new(0.25);
+        let two = Scalar::constant(2.0);
+        let three = Scalar::constant(3.0);
+
+        // x * y is built twice, and (2 * 3)^2 only depends on constants
+        let first = (&x * &y).tanh();
+        let second = (&x * &y).tanh();
+        let scale = (&two * &three).pow(2.0);
+        let output = &(&(&first + &second) * &scale) + &(&w * &(&x * &y).exp()).relu();
+
+        let inputs = [("x", &x), ("y", &y)];
+        let parameters = [w.clone()];
+        let mut original = Tape::trace(&output, &inputs, &parameters).unwrap();
+        let mut optimized = Tape::trace(&output, &inputs, &parameters).unwrap();
+        optimized.optimize();
+        assert!(optimized.len() < original.len());
+
+        output.backward();
+        for (x_data, y_data) in [(-2.0, 0.75), (1.0, 2.0)] {
+            for tape in [&mut original, &mut optimized] {
+                tape.set_input("x", x_data).unwrap();
+                tape.set_input("y", y_data).unwrap();
+                tape.forward();
+                tape.backward();
+            }
+            assert_close(optimized.output(), original.output());
+            for name in ["x", "y"] {
+                assert_close(optimized.grad(name).unwrap(), original.grad(name).unwrap());
+            }
+            let (_, w_grad) = optimized.parameter_grads().next().unwrap();
+            assert_close(w_grad, original.parameter_grads().next().unwrap().1);
+        }
+
+        // The first inputs are the values the Scalar graph was built with
+        for tape in [&mut original, &mut optimized] {
+            tape.set_input("x", 0.5).unwrap();
+            tape.set_input("y", -1.5).unwrap();
+            tape.forward();
+            tape.backward();
+            assert_close(tape.output(), output.get_data());
+            assert_close(tape.grad("x").unwrap(), x.get_grad());
+            assert_close(tape.grad("y").unwrap(), y.get_grad());
+            assert_close(tape.parameter_grads().next().unwrap().1, w.get_grad());
+        }
+    }
+}
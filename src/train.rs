@@ -0,0 +1,134 @@
+use std::fmt::Display;
+
+use num_traits::Float;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use crate::nn::check_num_inputs;
+use crate::{Architecture, Module, MultiLayerPerceptron, NnError, SharedParameters, Var};
+
+/// Trains a `MultiLayerPerceptron` with mini-batch gradient descent, splitting each mini-batch across worker threads.
+/// Every worker builds its samples' graphs in its own arena `Graph`, from a copy of the model's parameters. The
+/// workers' gradients are averaged into the model's parameters before each update.
+///
+/// Given the same seed, training is deterministic, whatever the number of threads: the mini-batches are shuffled from
+/// the seed, and each sample's gradients are summed in mini-batch order.
+pub struct DataParallelTrainer<T> {
+    pub learning_rate: T,
+    pub batch_size: usize,
+    pub num_threads: usize,
+    rng: StdRng,
+}
+
+impl<T: Float + Copy + Display + std::ops::AddAssign + Send + Sync + 'static> DataParallelTrainer<T> {
+    pub fn new(learning_rate: T, batch_size: usize, num_threads: usize, seed: u64) -> Self {
+        DataParallelTrainer {
+            learning_rate,
+            batch_size,
+            num_threads,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Make one pass over the samples in shuffled mini-batches, minimising the squared error between the model's
+    /// outputs and `ys`. Returns the mean loss per sample. If there isn't a target for every input, or a sample doesn't
+    /// fit the model, returns an error before changing anything.
+    pub fn train_epoch(&mut self, mlp: &MultiLayerPerceptron<T>, xs: &[Vec<T>], ys: &[Vec<T>]) -> Result<T, NnError> {
+        check_samples(&mlp.architecture(), xs, ys)?;
+        let mut order: Vec<usize> = (0..xs.len()).collect();
+        order.shuffle(&mut self.rng);
+
+        let mut total_loss = T::zero();
+        for batch in order.chunks(self.batch_size.max(1)) {
+            total_loss += self.train_batch(mlp, xs, ys, batch);
+        }
+        Ok(total_loss / T::from(xs.len().max(1)).unwrap())
+    }
+
+    // Returns the total loss over the batch. The samples have been checked against the model.
+    fn train_batch(&self, mlp: &MultiLayerPerceptron<T>, xs: &[Vec<T>], ys: &[Vec<T>], batch: &[usize]) -> T {
+        let parameters = mlp.parameters();
+        let architecture = mlp.architecture();
+        let shared = SharedParameters::from_scalars(&parameters);
+        let loss = shared.backward_batch(batch, self.num_threads, |graph, parameters, sample| {
+            let inputs: Vec<Var<T>> = xs[*sample].iter().map(|x| graph.var(*x)).collect();
+            let predictions = architecture.forward_var(parameters, &inputs).unwrap();
+            let mut loss = graph.var(T::zero());
+            for (prediction, y) in predictions.iter().zip(ys[*sample].iter()) {
+                loss = &loss + &(prediction - &graph.var(*y)).pow(T::from(2.0).unwrap());
+            }
+            loss
+        });
+
+        let batch_size = T::from(batch.len()).unwrap();
+        mlp.zero_grad();
+        for (p, grad) in parameters.iter().zip(shared.get_grads()) {
+            p.add_to_grad(grad / batch_size);
+            p.add_to_data(p.get_grad() * -self.learning_rate);
+        }
+        loss
+    }
+}
+
+// A sample that doesn't fit the model would make a worker panic, or silently misalign the weights or targets
+fn check_samples<T>(architecture: &Architecture, xs: &[Vec<T>], ys: &[Vec<T>]) -> Result<(), NnError> {
+    if ys.len() != xs.len() {
+        Err(NnError::ShapeMismatch { expected: xs.len(), actual: ys.len() })?
+    }
+    let num_outputs = *architecture.layer_num_outputs.last().ok_or(NnError::EmptyModel)?;
+    for (x, y) in xs.iter().zip(ys) {
+        check_num_inputs(architecture.num_inputs, x)?;
+        check_num_inputs(num_outputs, y)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples() -> (Vec<Vec<f64>>, Vec<Vec<f64>>) {
+        let xs = vec![vec![2.0, 3.0, -1.0], vec![3.0, -1.0, 0.5], vec![0.5, 1.0, 1.0], vec![1.0, 1.0, -1.0],
+                      vec![-2.0, 0.5, 1.5], vec![0.0, -1.0, 2.0], vec![1.5, 2.5, -0.5]];
+        let ys = [1.0, -1.0, -1.0, 1.0, -1.0, 1.0, 1.0].iter().map(|y| vec![*y]).collect();
+        (xs, ys)
+    }
+
+    #[test]
+    fn training_does_not_depend_on_the_number_of_threads() {
+        let (xs, ys) = samples();
+        let weights: Vec<Vec<u64>> = [1, 2, 3, 8].iter().map(|num_threads| {
+            let mlp = MultiLayerPerceptron::builder(3).layers(&[4, 4, 1]).seed(7).build().expect("Invalid model");
+            let mut trainer = DataParallelTrainer::new(0.05, 5, *num_threads, 11);
+            for _ in 0..3 {
+                trainer.train_epoch(&mlp, &xs, &ys).expect("Samples don't fit the model");
+            }
+            mlp.parameters().iter().map(|p| p.get_data().to_bits()).collect()
+        }).collect();
+        assert!(weights.iter().all(|w| *w == weights[0]));
+    }
+
+    #[test]
+    fn samples_that_do_not_fit_the_model_are_rejected_before_training() {
+        let (xs, ys) = samples();
+        let mut wide_input = xs.clone();
+        wide_input[2].push(1.0);
+        let mut wide_target = ys.clone();
+        wide_target[4].push(1.0);
+        let cases = [
+            (xs.clone(), ys[..6].to_vec(), NnError::ShapeMismatch { expected: 7, actual: 6 }),
+            (xs[..6].to_vec(), ys.clone(), NnError::ShapeMismatch { expected: 6, actual: 7 }),
+            (wide_input, ys.clone(), NnError::ShapeMismatch { expected: 3, actual: 4 }),
+            (xs.clone(), wide_target, NnError::ShapeMismatch { expected: 1, actual: 2 }),
+            (xs, vec![vec![]; 7], NnError::ShapeMismatch { expected: 1, actual: 0 }),
+        ];
+        for (xs, ys, error) in cases {
+            let mlp = MultiLayerPerceptron::builder(3).layers(&[4, 1]).seed(7).build().expect("Invalid model");
+            let before: Vec<f64> = mlp.parameters().iter().map(|p| p.get_data()).collect();
+            let mut trainer = DataParallelTrainer::new(0.05, 2, 2, 11);
+            assert_eq!(trainer.train_epoch(&mlp, &xs, &ys), Err(error));
+            assert_eq!(mlp.parameters().iter().map(|p| p.get_data()).collect::<Vec<f64>>(), before);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+"""Writes the .npy and .npz fixtures read by the tests in src/io.rs with numpy's own np.save and np.savez, and records
+the numpy version that wrote them in numpy_version.txt. Run it from this directory.
+"""
+
+import numpy as np
+
+if __name__ == "__main__":
+    np.save("numpy_f8.npy", np.array([[1.5, -2.0, 0.25], [3.0, 0.0, -1.0]]))
+    np.savez("numpy.npz", weight=np.array([[0.1, 0.2], [0.3, 0.4]], dtype=np.float32), bias=np.array([-0.5]))
+    with open("numpy_version.txt", "w") as f:
+        f.write(np.__version__ + "\n")